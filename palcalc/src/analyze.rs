@@ -0,0 +1,288 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    colorcalc::{CalcOptions, ColorCalc, ColorData},
+    colors::{self, FloatColor, Lab},
+    config::Config,
+    histogram::{self, Group, Histogram, InputFile},
+    interface::{StatusCalculating, Tui},
+    palette::Palette,
+};
+
+/// Number of equally wide luminance bins
+const LUMINANCE_BINS: usize = 8;
+/// Width of a hue bin in degrees
+const HUE_BIN: f64 = 30.0;
+/// Colors below this HSV saturation count as gray instead of having a hue
+const GRAY_SATURATION: f64 = 0.1;
+
+#[derive(Debug, Serialize)]
+pub struct FileStats {
+    pub path: String,
+    pub group: Group,
+    pub pixels: f64,
+    pub unique_colors: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ColorShare {
+    pub color: String,
+    /// Fraction of the weighted pixels
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HueBin {
+    /// Lowest hue angle of the bin in degrees
+    pub from: f64,
+    pub share: f64,
+}
+
+/// Quantization error of a palette of `colors` entries fitted to the inputs
+#[derive(Debug, Serialize)]
+pub struct ErrorPoint {
+    pub colors: u32,
+    pub rms_error: f64,
+}
+
+/// Weighted mean error of the inputs mapped to an existing palette
+#[derive(Debug, Serialize)]
+pub struct PaletteError {
+    pub colors: usize,
+    pub mean_error: f64,
+    pub mean_delta_e: f64,
+}
+
+/// Color statistics of the inputs, merged the same way as for the palette
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    pub files: Vec<FileStats>,
+    pub unique_colors: usize,
+    pub top_colors: Vec<ColorShare>,
+    /// Share of weighted pixels per luminance bin, dark to bright
+    pub luminance: Vec<f64>,
+    pub hue: Vec<HueBin>,
+    /// Share of weighted pixels too unsaturated to have a hue
+    pub gray: f64,
+    pub error_curve: Vec<ErrorPoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette_error: Option<PaletteError>,
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Powers of two from 2 up to `max`, followed by `max` itself
+fn curve_sizes(max: u32) -> Vec<u32> {
+    let mut sizes: Vec<u32> = (1..8).map(|bits| 1 << bits).filter(|&size| size < max).collect();
+    sizes.push(max);
+    sizes
+}
+
+/// Mean RGB distance in 8-bit units and CIEDE2000 difference of every point to its nearest palette entry
+fn palette_error(points: &[(FloatColor, f64)], palette: &Palette) -> PaletteError {
+    let entries = palette.float_colors();
+    let lab: Vec<Lab> = entries.iter().map(|&color| Lab::from_rgb(color)).collect();
+    let (total, error, delta_e) = points
+        .par_iter()
+        .map(|&(color, count)| {
+            let index = colors::nearest(&entries, color);
+            (
+                count,
+                color.distance(entries[index]) * 255.0 * count,
+                Lab::from_rgb(color).delta_e(lab[index]) * count,
+            )
+        })
+        .reduce(|| (0.0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+    let mean = |sum: f64| if total > 0.0 { sum / total } else { 0.0 };
+    PaletteError {
+        colors: entries.len(),
+        mean_error: mean(error),
+        mean_delta_e: mean(delta_e),
+    }
+}
+
+impl Analysis {
+    /// Gathers the statistics and clusters the inputs once per palette size up to `max_colors`,
+    /// each size starting from the centroids of the previous one. Also measures `palette` if given.
+    pub fn new(
+        histograms: &[(InputFile, Histogram)],
+        config: &Config,
+        top: usize,
+        max_colors: u32,
+        palette: Option<&Palette>,
+        tui: &mut Tui,
+    ) -> Result<Analysis> {
+        let files = histograms
+            .iter()
+            .map(|(file, histogram)| FileStats {
+                path: file.path.to_string_lossy().into_owned(),
+                group: file.group,
+                pixels: histogram.pixels,
+                unique_colors: histogram.colors.len(),
+            })
+            .collect();
+
+        let group_weights = config.group_weights();
+        let mut data = ColorData::new();
+        for group in [Group::Main, Group::Texture, Group::Fixed, Group::Fullbright] {
+            histogram::merge(histograms, config.normalize, &group_weights, &mut data, group);
+        }
+        let mut colors = data.colors();
+        drop(data);
+        let total: f64 = colors.iter().map(|(_, count)| count).sum();
+        let share = |count: f64| if total > 0.0 { count / total } else { 0.0 };
+
+        let mut luminance = vec![0.0; LUMINANCE_BINS];
+        let hue_bins = (360.0 / HUE_BIN) as usize;
+        let mut hue = vec![0.0; hue_bins];
+        let mut gray = 0.0;
+        for &(color, count) in colors.iter() {
+            let color = FloatColor::from_rgb8(color);
+            let bin = (color.luminance() * LUMINANCE_BINS as f64) as usize;
+            luminance[bin.min(LUMINANCE_BINS - 1)] += share(count);
+            if color.saturation() < GRAY_SATURATION {
+                gray += share(count);
+            } else {
+                let bin = (color.hue() / HUE_BIN) as usize;
+                hue[bin.min(hue_bins - 1)] += share(count);
+            }
+        }
+
+        colors.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let top_colors = colors
+            .iter()
+            .take(top)
+            .map(|&(color, count)| ColorShare {
+                color: hex(color),
+                share: share(count),
+            })
+            .collect();
+
+        let points: Vec<(FloatColor, f64)> = colors
+            .iter()
+            .map(|&(color, count)| (FloatColor::from_rgb8(color), count))
+            .collect();
+        let mut error_curve = vec![];
+        let mut seeds: Vec<FloatColor> = vec![];
+        for size in curve_sizes(max_colors) {
+            let options = CalcOptions {
+                colors: size,
+                attempts: 1,
+                ..config.calc_options()
+            };
+            let mut calculator = ColorCalc::from_points(&options, points.clone(), &[])?;
+            calculator.seed_centroids(&seeds.iter().map(|color| color.to_rgb8()).collect::<Vec<_>>());
+            let mut status = StatusCalculating::new(
+                tui,
+                &format!("Estimating error for {} colors", size),
+                1,
+                options.max_steps,
+                calculator.unique_colors,
+                0,
+            )?;
+            calculator.run(&mut status, tui)?;
+            seeds = calculator.best_centroids().to_vec();
+            error_curve.push(ErrorPoint {
+                colors: size,
+                rms_error: calculator.rms_error(),
+            });
+        }
+
+        let palette_error = palette.map(|palette| palette_error(&points, palette));
+
+        Ok(Analysis {
+            files,
+            unique_colors: colors.len(),
+            top_colors,
+            luminance,
+            hue: hue
+                .into_iter()
+                .enumerate()
+                .map(|(i, share)| HueBin {
+                    from: i as f64 * HUE_BIN,
+                    share,
+                })
+                .collect(),
+            gray,
+            error_curve,
+            palette_error,
+        })
+    }
+
+    /// Rows of palette size, error and the improvement over the previous size
+    pub fn curve_rows(&self) -> Vec<Vec<String>> {
+        let mut previous: Option<f64> = None;
+        self.error_curve
+            .iter()
+            .map(|point| {
+                let gain = match previous {
+                    Some(previous) => format!("{:.2}", previous - point.rms_error),
+                    None => String::new(),
+                };
+                previous = Some(point.rms_error);
+                vec![point.colors.to_string(), format!("{:.2}", point.rms_error), gain]
+            })
+            .collect()
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let percent = |share: f64| format!("{:.1}%", share * 100.0);
+        let mut lines = vec![format!(
+            "{} unique colors in {} files",
+            self.unique_colors,
+            self.files.len()
+        )];
+        for file in self.files.iter() {
+            lines.push(format!(
+                "  {} ({}): {} colors, {} pixels",
+                file.path,
+                file.group.name(),
+                file.unique_colors,
+                file.pixels.round()
+            ));
+        }
+        lines.push("Most frequent colors:".to_string());
+        for color in self.top_colors.iter() {
+            lines.push(format!("  {} {}", color.color, percent(color.share)));
+        }
+        lines.push(format!(
+            "Luminance, dark to bright: {}",
+            self.luminance
+                .iter()
+                .map(|&share| percent(share))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+        lines.push(format!(
+            "Hue: {}, gray {}",
+            self.hue
+                .iter()
+                .map(|bin| format!("{}° {}", bin.from, percent(bin.share)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            percent(self.gray)
+        ));
+        lines.push("RMS error by palette size:".to_string());
+        for row in self.curve_rows() {
+            lines.push(format!("  {:>3} colors: {}", row[0], row[1]));
+        }
+        if let Some(error) = &self.palette_error {
+            lines.push(format!(
+                "Given palette of {} colors: mean error {:.2}, mean dE2000 {:.2}",
+                error.colors, error.mean_error, error.mean_delta_e
+            ));
+        }
+        lines
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,734 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use glob::{MatchOptions, Pattern};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+use crate::{inputs, palette::Palette};
+
+/// Separates the archive path from the lump name, as in doom.wad:FLOOR4_8
+const SEPARATOR: char = ':';
+/// Files next to an archive that provide the palette if the archive has none
+const SIBLING_PALETTES: [&str; 4] = ["PLAYPAL.lmp", "playpal.lmp", "palette.lmp", "PALETTE.lmp"];
+/// PAK lumps that share the picture extension without being pictures
+const PAK_TABLES: [&str; 2] = ["palette.lmp", "colormap.lmp"];
+/// Quake and Half-Life draw index 255 of pictures and of textures named {... as transparent
+const QUAKE_TRANSPARENT: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Doom 64x64 floor texture, raw indices
+    Flat,
+    /// Doom picture format, used by wall patches and sprites
+    Patch,
+    /// Quake texture with mipmaps, drawn with the archive palette
+    Miptex,
+    /// Half-Life texture with mipmaps, carrying its own palette
+    Miptex3,
+    /// Quake width and height followed by raw indices
+    Picture,
+    /// Standalone image file inside a PAK
+    Image,
+}
+
+struct Entry {
+    name: String,
+    offset: u64,
+    size: usize,
+    kind: Option<Kind>,
+    /// WAD2 lumps can be compressed, which isn't supported
+    compressed: bool,
+}
+
+/// Directory of a Doom WAD, a Quake or Half-Life WAD2/WAD3 or a Quake PAK
+struct Archive {
+    path: PathBuf,
+    file: File,
+    entries: Vec<Entry>,
+    /// Loaded on first use, archives of Half-Life textures don't need one
+    palette: Option<Palette>,
+}
+
+type SharedArchive = Arc<Mutex<Archive>>;
+
+/// Archives opened so far, so that every archive is opened and its directory read once per run
+static OPEN_ARCHIVES: LazyLock<Mutex<HashMap<PathBuf, SharedArchive>>> = LazyLock::new(Default::default);
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ["wad", "pak"].contains(&extension.to_lowercase().as_str()))
+}
+
+/// Splits doom.wad:FLOOR4_8 into the archive path and the lump name, None for plain files
+pub fn split(path: &Path) -> Option<(PathBuf, String)> {
+    let path = path.to_str()?;
+    // Later separators win, so that drive letters stay part of the archive path
+    let (archive, name) = path.rsplit_once(SEPARATOR)?;
+    let archive = PathBuf::from(archive);
+    (is_archive(&archive) && archive.is_file()).then(|| (archive, name.to_string()))
+}
+
+fn join(archive: &Path, name: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}{}", archive.display(), SEPARATOR, name))
+}
+
+/// Image lumps of `archive` whose names match `pattern`, all of them without one.
+/// Lumps whose header doesn't fit their size are skipped with a warning.
+pub fn expand(archive: &Path, pattern: Option<&str>) -> Result<Vec<PathBuf>> {
+    let shared = Archive::shared(archive)?;
+    let mut archive_file = shared.lock().unwrap();
+    let pattern = pattern.map(Pattern::new).transpose()?;
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+    let mut lumps = vec![];
+    for index in 0..archive_file.entries.len() {
+        let entry = &archive_file.entries[index];
+        if entry.kind.is_none() || !pattern.as_ref().is_none_or(|p| p.matches_with(&entry.name, options)) {
+            continue;
+        }
+        let path = join(archive, &entry.name);
+        if entry.compressed {
+            eprintln!("Skipping {}: compressed lumps aren't supported", path.display());
+            continue;
+        }
+        match archive_file.check(index) {
+            Ok(()) => lumps.push(path),
+            Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(lumps)
+}
+
+/// Decodes a lump to RGBA, skipped pixels of patches, sprites and transparent Quake images get alpha 0
+pub fn open_image(archive: &Path, name: &str) -> Result<DynamicImage> {
+    let shared = Archive::shared(archive)?;
+    let mut archive = shared.lock().unwrap();
+    let index = archive
+        .find(name)
+        .ok_or_else(|| anyhow!("no lump {} in {}", name, archive.path.display()))?;
+    let entry = &archive.entries[index];
+    if entry.compressed {
+        return Err(anyhow!("{} in {} is compressed", name, archive.path.display()));
+    }
+    let (kind, entry_name) = (
+        entry
+            .kind
+            .ok_or_else(|| anyhow!("{} in {} is not an image", name, archive.path.display()))?,
+        entry.name.clone(),
+    );
+    let data = archive.read(index)?;
+    let palette = match kind {
+        Kind::Image | Kind::Miptex3 => None,
+        _ => Some(archive.palette()?.colors.clone()),
+    };
+    // Decoding doesn't need the archive, so other threads can read from it meanwhile
+    drop(archive);
+    let transparent = entry_name.starts_with('{');
+    let indexed = match kind {
+        Kind::Image => {
+            let format = ImageFormat::from_path(&entry_name)?;
+            return Ok(image::load_from_memory_with_format(&data, format)?);
+        }
+        Kind::Miptex3 => return miptex3(&data, transparent),
+        Kind::Flat => flat(&data)?,
+        Kind::Patch => patch(&data)?,
+        Kind::Miptex => miptex(&data, transparent)?,
+        Kind::Picture => picture(&data)?,
+    };
+    Ok(indexed.to_image(&palette.unwrap_or_default()))
+}
+
+/// Palette indices of an image, None for transparent pixels
+struct Indexed {
+    width: u32,
+    height: u32,
+    pixels: Vec<Option<u8>>,
+}
+
+impl Indexed {
+    fn to_image(&self, palette: &[[u8; 3]]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(self.width, self.height, |x, y| {
+            match self.pixels[(y * self.width + x) as usize] {
+                Some(index) => {
+                    let [r, g, b] = palette.get(index as usize).copied().unwrap_or([0, 0, 0]);
+                    image::Rgba([r, g, b, 255])
+                }
+                None => image::Rgba([0, 0, 0, 0]),
+            }
+        }))
+    }
+}
+
+fn bytes(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| anyhow!("truncated lump"))
+}
+
+fn u16_le(data: &[u8], at: usize) -> Result<usize> {
+    let b = bytes(data, at, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+}
+
+fn u32_le(data: &[u8], at: usize) -> Result<usize> {
+    let b = bytes(data, at, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+/// Lump name stored as a NUL padded field
+fn name(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Side length of a square flat of `size` bytes
+fn flat_side(size: usize) -> Result<usize> {
+    let side = (size as f64).sqrt() as usize;
+    if side == 0 || side * side != size {
+        return Err(anyhow!("flat of {} bytes isn't square", size));
+    }
+    Ok(side)
+}
+
+/// Square block of raw indices, 64x64 for Doom
+fn flat(data: &[u8]) -> Result<Indexed> {
+    let side = flat_side(data.len())?;
+    Ok(Indexed {
+        width: side as u32,
+        height: side as u32,
+        pixels: data.iter().map(|&index| Some(index)).collect(),
+    })
+}
+
+/// Doom picture: a column offset table, every column a list of posts ending in 0xff
+fn patch(data: &[u8]) -> Result<Indexed> {
+    let (width, height) = (u16_le(data, 0)?, u16_le(data, 2)?);
+    let mut pixels = vec![None; width * height];
+    for x in 0..width {
+        let mut at = u32_le(data, 8 + x * 4)?;
+        let mut top = None;
+        loop {
+            let delta = bytes(data, at, 1)?[0] as usize;
+            if delta == 0xff {
+                break;
+            }
+            // Tall patches continue a post below the previous one with a relative offset
+            let start = match top {
+                Some(top) if delta <= top => top + delta,
+                _ => delta,
+            };
+            top = Some(start);
+            let length = bytes(data, at + 1, 1)?[0] as usize;
+            for (i, &index) in bytes(data, at + 3, length)?.iter().enumerate() {
+                if start + i < height {
+                    pixels[(start + i) * width + x] = Some(index);
+                }
+            }
+            at += length + 4;
+        }
+    }
+    Ok(Indexed {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+/// `width * height` indices starting at `start`, with index 255 transparent if `transparent` is set
+fn block(data: &[u8], width: usize, height: usize, start: usize, transparent: bool) -> Result<Indexed> {
+    let pixels = bytes(data, start, width.saturating_mul(height))?
+        .iter()
+        .map(|&index| (!transparent || index != QUAKE_TRANSPARENT).then_some(index))
+        .collect();
+    Ok(Indexed {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+fn picture(data: &[u8]) -> Result<Indexed> {
+    block(data, u32_le(data, 0)?, u32_le(data, 4)?, 8, true)
+}
+
+/// Full size level of a Quake texture
+fn miptex(data: &[u8], transparent: bool) -> Result<Indexed> {
+    block(
+        data,
+        u32_le(data, 16)?,
+        u32_le(data, 20)?,
+        u32_le(data, 24)?,
+        transparent,
+    )
+}
+
+/// Half-Life texture, its palette follows the smallest mipmap
+fn miptex3(data: &[u8], transparent: bool) -> Result<DynamicImage> {
+    let (width, height) = (u32_le(data, 16)?, u32_le(data, 20)?);
+    let indexed = block(data, width, height, u32_le(data, 24)?, transparent)?;
+    let end = u32_le(data, 36)? + (width / 8) * (height / 8);
+    let count = u16_le(data, end)?;
+    let palette: Vec<[u8; 3]> = bytes(data, end + 2, count * 3)?
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect();
+    Ok(indexed.to_image(&palette))
+}
+
+/// Kind of a Doom lump between X_START and X_END markers
+fn marker_kind(marker: &str) -> Option<Kind> {
+    match marker.trim_end_matches(|c: char| c.is_ascii_digit()) {
+        "F" | "FF" => Some(Kind::Flat),
+        "P" | "PP" | "S" | "SS" => Some(Kind::Patch),
+        _ => None,
+    }
+}
+
+impl Archive {
+    fn shared(path: &Path) -> Result<SharedArchive> {
+        let mut open = OPEN_ARCHIVES.lock().unwrap();
+        if let Some(archive) = open.get(path) {
+            return Ok(archive.clone());
+        }
+        let archive = Arc::new(Mutex::new(Archive::open(path)?));
+        open.insert(path.to_path_buf(), archive.clone());
+        Ok(archive)
+    }
+
+    fn open(path: &Path) -> Result<Archive> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)?;
+        let (first, second) = (u32_le(&header, 4)?, u32_le(&header, 8)?);
+        let entries = match &header[..4] {
+            b"IWAD" | b"PWAD" => Archive::doom_entries(&read_at(&mut file, second as u64, first * 16)?)?,
+            magic @ (b"WAD2" | b"WAD3") => {
+                Archive::wad2_entries(&read_at(&mut file, second as u64, first * 32)?, magic == b"WAD3")?
+            }
+            b"PACK" => Archive::pak_entries(&read_at(&mut file, first as u64, second)?)?,
+            _ => return Err(anyhow!("{} is not a WAD or PAK archive", path.display())),
+        };
+        Ok(Archive {
+            path: path.to_path_buf(),
+            file,
+            entries,
+            palette: None,
+        })
+    }
+
+    fn doom_entries(directory: &[u8]) -> Result<Vec<Entry>> {
+        let mut markers: Vec<Kind> = vec![];
+        let mut entries = vec![];
+        for record in directory.chunks_exact(16) {
+            let name = name(&record[8..16]).to_uppercase();
+            let size = u32_le(record, 4)?;
+            let kind = if let Some(marker) = name.strip_suffix("_START") {
+                markers.extend(marker_kind(marker));
+                None
+            } else if let Some(marker) = name.strip_suffix("_END") {
+                if marker_kind(marker).is_some() {
+                    markers.pop();
+                }
+                None
+            } else {
+                markers.last().copied().filter(|_| size > 0)
+            };
+            entries.push(Entry {
+                name,
+                offset: u32_le(record, 0)? as u64,
+                size,
+                kind,
+                compressed: false,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn wad2_entries(directory: &[u8], wad3: bool) -> Result<Vec<Entry>> {
+        directory
+            .chunks_exact(32)
+            .map(|record| {
+                let kind = match record[12] {
+                    0x42 if !wad3 => Some(Kind::Picture),
+                    0x44 if !wad3 => Some(Kind::Miptex),
+                    0x43 if wad3 => Some(Kind::Miptex3),
+                    _ => None,
+                };
+                Ok(Entry {
+                    name: name(&record[16..32]).to_uppercase(),
+                    offset: u32_le(record, 0)? as u64,
+                    size: u32_le(record, 4)?,
+                    kind,
+                    compressed: record[13] != 0,
+                })
+            })
+            .collect()
+    }
+
+    fn pak_entries(directory: &[u8]) -> Result<Vec<Entry>> {
+        directory
+            .chunks_exact(64)
+            .map(|record| {
+                let name = name(&record[..56]);
+                let size = u32_le(record, 60)?;
+                let path = Path::new(&name);
+                let kind = if inputs::is_supported_image(path) {
+                    Some(Kind::Image)
+                } else if path.extension().is_some_and(|extension| extension == "lmp")
+                    && !path
+                        .file_name()
+                        .is_some_and(|file| PAK_TABLES.iter().any(|table| file == *table))
+                {
+                    Some(Kind::Picture)
+                } else {
+                    None
+                };
+                Ok(Entry {
+                    name,
+                    offset: u32_le(record, 56)? as u64,
+                    size,
+                    kind,
+                    compressed: false,
+                })
+            })
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        // Later lumps replace earlier ones of the same name
+        self.entries
+            .iter()
+            .rposition(|entry| entry.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Checks that the header of an image lump matches its size, without decoding it
+    fn check(&mut self, index: usize) -> Result<()> {
+        let (kind, size) = (self.entries[index].kind, self.entries[index].size);
+        let header = |archive: &mut Archive, len: usize| -> Result<Vec<u8>> {
+            let entry = &archive.entries[index];
+            read_at(&mut archive.file, entry.offset, len.min(entry.size))
+        };
+        match kind {
+            Some(Kind::Flat) => {
+                flat_side(size)?;
+            }
+            Some(Kind::Picture) => {
+                let data = header(self, 8)?;
+                let (width, height) = (u32_le(&data, 0)?, u32_le(&data, 4)?);
+                if width.saturating_mul(height).saturating_add(8) != size {
+                    return Err(anyhow!("{} bytes are no {}x{} picture", size, width, height));
+                }
+            }
+            Some(Kind::Miptex | Kind::Miptex3) => {
+                let data = header(self, 40)?;
+                let (width, height, start) = (u32_le(&data, 16)?, u32_le(&data, 20)?, u32_le(&data, 24)?);
+                if width.saturating_mul(height).saturating_add(start) > size {
+                    return Err(anyhow!("{}x{} texture doesn't fit into {} bytes", width, height, size));
+                }
+            }
+            Some(Kind::Patch) => {
+                let data = header(self, 4)?;
+                if 8 + u16_le(&data, 0)? * 4 > size {
+                    return Err(anyhow!("column table doesn't fit into {} bytes", size));
+                }
+            }
+            Some(Kind::Image) | None => {}
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, index: usize) -> Result<Vec<u8>> {
+        let entry = &self.entries[index];
+        read_at(&mut self.file, entry.offset, entry.size)
+    }
+
+    /// Palette of the archive, looked up on first use
+    fn palette(&mut self) -> Result<&Palette> {
+        if self.palette.is_none() {
+            self.palette = Some(self.find_palette()?);
+        }
+        Ok(self.palette.as_ref().unwrap())
+    }
+
+    /// PLAYPAL of a Doom WAD, PALETTE of a WAD2 or gfx/palette.lmp of a PAK, otherwise a palette
+    /// file next to the archive
+    fn find_palette(&mut self) -> Result<Palette> {
+        for name in ["PLAYPAL", "PALETTE", "gfx/palette.lmp"] {
+            if let Some(index) = self.find(name) {
+                // All three are raw RGB triplets, which the name PLAYPAL selects
+                return Palette::decode(Path::new("PLAYPAL"), &self.read(index)?);
+            }
+        }
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        match SIBLING_PALETTES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+        {
+            Some(path) => Palette::read(&path),
+            None => Err(anyhow!(
+                "{} has no palette, put a palette.lmp next to it",
+                self.path.display()
+            )),
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Palette whose entry i is (i, 255 - i, 0), so decoded colors give away their index
+    fn palette_data() -> Vec<u8> {
+        (0..=255u8).flat_map(|i| [i, 255 - i, 0]).collect()
+    }
+
+    fn color(index: u8) -> image::Rgba<u8> {
+        image::Rgba([index, 255 - index, 0, 255])
+    }
+
+    fn write_archive(file_name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("palcalc-{}-{}", std::process::id(), file_name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn padded_name(name: &str, len: usize) -> Vec<u8> {
+        let mut field = name.as_bytes().to_vec();
+        field.resize(len, 0);
+        field
+    }
+
+    fn doom_wad(lumps: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut data = b"PWAD".to_vec();
+        let directory_offset = 12 + lumps.iter().map(|(_, lump)| lump.len()).sum::<usize>();
+        data.extend((lumps.len() as u32).to_le_bytes());
+        data.extend((directory_offset as u32).to_le_bytes());
+        let mut directory = vec![];
+        for (name, lump) in lumps {
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend((lump.len() as u32).to_le_bytes());
+            directory.extend(padded_name(name, 8));
+            data.extend(lump);
+        }
+        data.extend(directory);
+        data
+    }
+
+    /// WAD2 or WAD3 with lumps given as name, type, compression and data
+    fn quake_wad(magic: &[u8; 4], lumps: &[(&str, u8, u8, Vec<u8>)]) -> Vec<u8> {
+        let mut data = magic.to_vec();
+        let directory_offset = 12 + lumps.iter().map(|(_, _, _, lump)| lump.len()).sum::<usize>();
+        data.extend((lumps.len() as u32).to_le_bytes());
+        data.extend((directory_offset as u32).to_le_bytes());
+        let mut directory = vec![];
+        for (name, kind, compression, lump) in lumps {
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend((lump.len() as u32).to_le_bytes());
+            directory.extend((lump.len() as u32).to_le_bytes());
+            directory.extend([*kind, *compression, 0, 0]);
+            directory.extend(padded_name(name, 16));
+            data.extend(lump);
+        }
+        data.extend(directory);
+        data
+    }
+
+    fn pak(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut data = b"PACK".to_vec();
+        let directory_offset = 12 + files.iter().map(|(_, file)| file.len()).sum::<usize>();
+        data.extend((directory_offset as u32).to_le_bytes());
+        data.extend((files.len() as u32 * 64).to_le_bytes());
+        let mut directory = vec![];
+        for (name, file) in files {
+            directory.extend(padded_name(name, 56));
+            directory.extend((data.len() as u32).to_le_bytes());
+            directory.extend((file.len() as u32).to_le_bytes());
+            data.extend(file);
+        }
+        data.extend(directory);
+        data
+    }
+
+    /// Quake picture: width and height followed by the indices
+    fn picture_lump(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        let mut data = width.to_le_bytes().to_vec();
+        data.extend(height.to_le_bytes());
+        data.extend(pixels);
+        data
+    }
+
+    /// 8x8 texture whose full size level has index 255 in its first pixel, with a palette for WAD3
+    fn miptex_lump(name: &str, palette: Option<&[u8]>) -> Vec<u8> {
+        let mut data = padded_name(name, 16);
+        data.extend(8u32.to_le_bytes());
+        data.extend(8u32.to_le_bytes());
+        for offset in [40u32, 104, 120, 124] {
+            data.extend(offset.to_le_bytes());
+        }
+        data.push(QUAKE_TRANSPARENT);
+        data.extend((1..64).map(|i| i as u8));
+        data.extend([0; 16 + 4 + 1]);
+        if let Some(palette) = palette {
+            data.extend(((palette.len() / 3) as u16).to_le_bytes());
+            data.extend(palette);
+        }
+        data
+    }
+
+    fn lump_names(lumps: &[PathBuf]) -> Vec<&str> {
+        lumps
+            .iter()
+            .map(|path| path.to_str().unwrap().rsplit_once(SEPARATOR).unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn doom_wad_markers_and_offsets() {
+        // 2x3 patch, column 0 has a post of two pixels starting at row 1, column 1 is empty
+        let mut patch = vec![2, 0, 3, 0, 0, 0, 0, 0];
+        patch.extend(16u32.to_le_bytes());
+        patch.extend(23u32.to_le_bytes());
+        patch.extend([1, 2, 0, 10, 20, 0, 0xff, 0xff]);
+        let path = write_archive(
+            "doom.wad",
+            &doom_wad(&[
+                ("PLAYPAL", palette_data()),
+                ("F_START", vec![]),
+                ("FLOOR", (0..16).collect()),
+                ("F_END", vec![]),
+                ("P_START", vec![]),
+                ("WALL", patch),
+                ("P_END", vec![]),
+                ("DEMO1", vec![1, 2, 3]),
+            ]),
+        );
+
+        let archive = Archive::open(&path).unwrap();
+        let kinds: Vec<_> = archive.entries.iter().map(|entry| entry.kind).collect();
+        assert_eq!(
+            kinds,
+            [None, None, Some(Kind::Flat), None, None, Some(Kind::Patch), None, None]
+        );
+        assert_eq!(archive.entries[0].offset, 12);
+        assert_eq!(archive.entries[2].offset, 12 + 768);
+        assert_eq!(archive.entries[2].size, 16);
+        assert_eq!(lump_names(&expand(&path, None).unwrap()), ["FLOOR", "WALL"]);
+        assert_eq!(lump_names(&expand(&path, Some("fl*")).unwrap()), ["FLOOR"]);
+
+        let floor = open_image(&path, "FLOOR").unwrap().to_rgba8();
+        assert_eq!(floor.dimensions(), (4, 4));
+        assert_eq!(*floor.get_pixel(1, 2), color(9));
+
+        let wall = open_image(&path, "wall").unwrap().to_rgba8();
+        assert_eq!(wall.dimensions(), (2, 3));
+        assert_eq!(wall.get_pixel(0, 0)[3], 0);
+        assert_eq!(*wall.get_pixel(0, 1), color(10));
+        assert_eq!(*wall.get_pixel(0, 2), color(20));
+        assert_eq!(wall.get_pixel(1, 1)[3], 0);
+    }
+
+    #[test]
+    fn wad2_skips_compressed_lumps() {
+        let path = write_archive(
+            "quake.wad",
+            &quake_wad(
+                b"WAD2",
+                &[
+                    ("PALETTE", 0x40, 0, palette_data()),
+                    ("CONCHARS", 0x42, 0, picture_lump(2, 1, &[QUAKE_TRANSPARENT, 7])),
+                    ("{FENCE", 0x44, 0, miptex_lump("{FENCE", None)),
+                    ("WALL", 0x44, 0, miptex_lump("WALL", None)),
+                    ("PACKED", 0x44, 1, vec![0; 8]),
+                ],
+            ),
+        );
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.entries[1].offset, 12 + 768);
+        assert!(archive.entries[4].compressed);
+        assert_eq!(
+            lump_names(&expand(&path, None).unwrap()),
+            ["CONCHARS", "{FENCE", "WALL"]
+        );
+        assert!(open_image(&path, "PACKED").is_err());
+
+        let conchars = open_image(&path, "CONCHARS").unwrap().to_rgba8();
+        assert_eq!(conchars.get_pixel(0, 0)[3], 0);
+        assert_eq!(*conchars.get_pixel(1, 0), color(7));
+
+        // Only textures named {... are keyed out
+        let fence = open_image(&path, "{FENCE").unwrap().to_rgba8();
+        assert_eq!(fence.dimensions(), (8, 8));
+        assert_eq!(fence.get_pixel(0, 0)[3], 0);
+        assert_eq!(*fence.get_pixel(1, 0), color(1));
+        let wall = open_image(&path, "WALL").unwrap().to_rgba8();
+        assert_eq!(*wall.get_pixel(0, 0), color(QUAKE_TRANSPARENT));
+    }
+
+    #[test]
+    fn wad3_textures_carry_their_palette() {
+        let own_palette: Vec<u8> = (0..=255u8).flat_map(|i| [0, 0, i]).collect();
+        let path = write_archive(
+            "halflife.wad",
+            &quake_wad(
+                b"WAD3",
+                &[
+                    ("{GRATE", 0x43, 0, miptex_lump("{GRATE", Some(&own_palette))),
+                    ("BRICK", 0x43, 0, miptex_lump("BRICK", Some(&own_palette))),
+                    ("QUAKE", 0x44, 0, miptex_lump("QUAKE", None)),
+                ],
+            ),
+        );
+
+        // WAD3 archives only hold Half-Life textures and need no archive palette
+        assert_eq!(lump_names(&expand(&path, None).unwrap()), ["{GRATE", "BRICK"]);
+        let grate = open_image(&path, "{GRATE").unwrap().to_rgba8();
+        assert_eq!(grate.get_pixel(0, 0)[3], 0);
+        assert_eq!(*grate.get_pixel(2, 0), image::Rgba([0, 0, 2, 255]));
+        let brick = open_image(&path, "BRICK").unwrap().to_rgba8();
+        assert_eq!(*brick.get_pixel(0, 0), image::Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn pak_skips_lumps_that_are_no_pictures() {
+        let path = write_archive(
+            "pak0.pak",
+            &pak(&[
+                ("gfx/palette.lmp", palette_data()),
+                ("gfx/colormap.lmp", vec![0; 64 * 256 + 1]),
+                ("gfx/conback.lmp", picture_lump(2, 2, &[1, 2, 3, 4])),
+                // Raw bitmask of the registered version check, not a picture
+                ("gfx/pop.lmp", (0..=255).collect()),
+                ("progs/player.mdl", vec![0; 16]),
+            ]),
+        );
+
+        let archive = Archive::open(&path).unwrap();
+        let kinds: Vec<_> = archive.entries.iter().map(|entry| entry.kind).collect();
+        assert_eq!(kinds, [None, None, Some(Kind::Picture), Some(Kind::Picture), None]);
+        assert_eq!(archive.entries[2].offset, 12 + 768 + 64 * 256 + 1);
+        assert_eq!(lump_names(&expand(&path, None).unwrap()), ["gfx/conback.lmp"]);
+
+        let conback = open_image(&path, "gfx/conback.lmp").unwrap().to_rgba8();
+        assert_eq!(*conback.get_pixel(1, 1), color(4));
+        assert!(open_image(&path, "gfx/pop.lmp").is_err());
+    }
+}
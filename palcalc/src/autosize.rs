@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::{
+    colorcalc::{CalcOptions, ColorCalc},
+    colors::FloatColor,
+    config::Config,
+    interface::{StatusCalculating, Tui},
+    palette,
+};
+
+/// Largest palette the search tries
+const MAX_COLORS: u32 = palette::MAX_COLORS as u32;
+
+/// Error targets for picking the palette size, a size has to meet all that are set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoColors {
+    /// Largest weighted mean pixel error, in 8-bit RGB units
+    pub max_error: Option<f64>,
+    /// Largest weighted mean CIEDE2000 difference
+    pub max_delta_e: Option<f64>,
+}
+
+/// Palette size given on the command line
+#[derive(Debug, Clone, Copy)]
+pub enum PaletteSize {
+    Count(u32),
+    Auto,
+}
+
+pub fn parse_size(s: &str) -> Result<PaletteSize> {
+    if s == "auto" {
+        Ok(PaletteSize::Auto)
+    } else {
+        Ok(PaletteSize::Count(s.parse()?))
+    }
+}
+
+/// One palette size tried during the search
+#[derive(Debug)]
+pub struct Trial {
+    pub colors: u32,
+    pub mean_error: f64,
+    pub mean_delta_e: f64,
+    pub meets: bool,
+}
+
+impl AutoColors {
+    pub fn check(&self) -> Result<()> {
+        if self.max_error.is_none() && self.max_delta_e.is_none() {
+            return Err(anyhow!("an automatic palette size needs a maximum error or delta E"));
+        }
+        Ok(())
+    }
+
+    fn meets(&self, mean_error: f64, mean_delta_e: f64) -> bool {
+        self.max_error.is_none_or(|max| mean_error <= max) && self.max_delta_e.is_none_or(|max| mean_delta_e <= max)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut targets = vec![];
+        if let Some(max) = self.max_error {
+            targets.push(format!("mean error <= {}", max));
+        }
+        if let Some(max) = self.max_delta_e {
+            targets.push(format!("mean dE2000 <= {}", max));
+        }
+        targets.join(", ")
+    }
+
+    /// Finds the smallest palette size meeting the targets by doubling the size until one does and
+    /// bisecting the last step. Every trial starts from the centroids of the largest size that failed.
+    /// Returns the largest size if none does.
+    pub fn search(
+        &self,
+        config: &Config,
+        points: &[(FloatColor, f64)],
+        fixed: &[[u8; 3]],
+        tui: &mut Tui,
+    ) -> Result<(u32, Vec<Trial>)> {
+        let ranges = config.range_entries() as u32;
+        // Explicitly placed ranges have to stay inside the palette
+        let range_end = config
+            .cycling
+            .ranges
+            .iter()
+            .map(|range| range.range().end)
+            .chain(
+                config
+                    .fullbright
+                    .iter()
+                    .filter_map(|fb| fb.start.map(|start| start + fb.count)),
+            )
+            .max()
+            .unwrap_or(0) as u32;
+        let smallest = (fixed.len() as u32 + ranges + 1).max(range_end).max(2);
+        if smallest > MAX_COLORS {
+            return Err(anyhow!(
+                "{} fixed colors and {} range entries don't fit into a palette",
+                fixed.len(),
+                ranges
+            ));
+        }
+
+        let mut trials: Vec<Trial> = vec![];
+        let mut seeds: Vec<[u8; 3]> = vec![];
+        let mut run = |colors: u32, seeds: &[[u8; 3]], trials: &mut Vec<Trial>| -> Result<(bool, Vec<[u8; 3]>)> {
+            let options = CalcOptions {
+                colors: colors - ranges,
+                attempts: 1,
+                ..config.calc_options()
+            };
+            let mut calculator = ColorCalc::from_points(&options, points.to_vec(), fixed)?;
+            calculator.seed_centroids(seeds);
+            let mut status = StatusCalculating::new(
+                tui,
+                &format!("Trying {} colors", colors),
+                1,
+                options.max_steps,
+                calculator.unique_colors,
+                calculator.fixed_colors,
+            )?;
+            calculator.run(&mut status, tui)?;
+            let (mean_error, mean_delta_e) = calculator.mean_errors();
+            let meets = self.meets(mean_error, mean_delta_e);
+            trials.push(Trial {
+                colors,
+                mean_error,
+                mean_delta_e,
+                meets,
+            });
+            Ok((
+                meets,
+                calculator
+                    .best_centroids()
+                    .iter()
+                    .map(|color| color.to_rgb8())
+                    .collect(),
+            ))
+        };
+
+        let (mut low, mut high) = (smallest - 1, None);
+        let mut colors = smallest;
+        loop {
+            let (meets, centroids) = run(colors, &seeds, &mut trials)?;
+            if meets {
+                high = Some(colors);
+                break;
+            }
+            (low, seeds) = (colors, centroids);
+            if colors == MAX_COLORS {
+                break;
+            }
+            colors = (colors * 2).min(MAX_COLORS);
+        }
+        let Some(mut high) = high else {
+            return Ok((MAX_COLORS, trials));
+        };
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            let (meets, centroids) = run(middle, &seeds, &mut trials)?;
+            if meets {
+                high = middle;
+            } else {
+                (low, seeds) = (middle, centroids);
+            }
+        }
+        Ok((high, trials))
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    archive,
+    histogram::{AlphaOptions, Histogram},
+};
+
+const MAGIC: &[u8; 4] = b"PCHC";
+const VERSION: u32 = 1;
+
+/// On-disk cache of per-file histograms.
+///
+/// Entries are keyed by the file path. An entry is reused as is while the file's
+/// mtime and size are unchanged, and revalidated by content hash otherwise.
+pub struct HistogramCache {
+    dir: PathBuf,
+    pub hits: u32,
+}
+
+struct Entry {
+    path: String,
+    mtime: u128,
+    size: u64,
+    hash: u64,
+    alpha: u64,
+    histogram: Histogram,
+}
+
+impl HistogramCache {
+    pub fn new(dir: &Path) -> Result<HistogramCache> {
+        fs::create_dir_all(dir)?;
+        Ok(HistogramCache {
+            dir: dir.to_path_buf(),
+            hits: 0,
+        })
+    }
+
+    pub fn load(&mut self, filename: &Path, alpha: &AlphaOptions) -> Result<Histogram> {
+        // Lumps are small and their archive changes as a whole, so they are decoded every time
+        if archive::split(filename).is_some() {
+            return Histogram::load(filename, alpha);
+        }
+        let path = filename.to_string_lossy().into_owned();
+        let entry_path = self.dir.join(format!("{:016x}.hist", xxh3_64(path.as_bytes())));
+        let metadata = fs::metadata(filename)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        let size = metadata.len();
+        let alpha_key = alpha_key(alpha);
+
+        let cached = fs::read(&entry_path)
+            .ok()
+            .and_then(|data| Entry::decode(&data).ok())
+            .filter(|entry| entry.path == path && entry.alpha == alpha_key);
+        let cached = match cached {
+            Some(entry) if entry.mtime == mtime && entry.size == size => {
+                self.hits += 1;
+                return Ok(entry.histogram);
+            }
+            cached => cached,
+        };
+
+        let data = fs::read(filename)?;
+        let hash = xxh3_64(&data);
+        let histogram = match cached {
+            Some(entry) if entry.hash == hash => {
+                self.hits += 1;
+                entry.histogram
+            }
+            _ => Histogram::decode(filename, &data, alpha)?,
+        };
+
+        let entry = Entry {
+            path,
+            mtime,
+            size,
+            hash,
+            alpha: alpha_key,
+            histogram,
+        };
+        // A failed cache write only costs a re-decode on the next run
+        let _ = fs::write(&entry_path, entry.encode());
+        Ok(entry.histogram)
+    }
+}
+
+fn alpha_key(alpha: &AlphaOptions) -> u64 {
+    let mut data = vec![alpha.threshold, alpha.weighted as u8];
+    for key in alpha.color_keys.iter() {
+        data.extend_from_slice(key);
+    }
+    xxh3_64(&data)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow!("unexpected end of cache entry"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into()?))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(64 + self.path.len() + self.histogram.colors.len() * 11);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.path.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.path.as_bytes());
+        data.extend_from_slice(&self.mtime.to_le_bytes());
+        data.extend_from_slice(&self.size.to_le_bytes());
+        data.extend_from_slice(&self.hash.to_le_bytes());
+        data.extend_from_slice(&self.alpha.to_le_bytes());
+        data.extend_from_slice(&self.histogram.pixels.to_le_bytes());
+        data.extend_from_slice(&(self.histogram.colors.len() as u32).to_le_bytes());
+        for (color, count) in self.histogram.colors.iter() {
+            data.extend_from_slice(color);
+            data.extend_from_slice(&count.to_le_bytes());
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<Entry> {
+        let mut reader = Reader(data);
+        if reader.take(4)? != MAGIC || reader.u32()? != VERSION {
+            return Err(anyhow!("not a histogram cache entry"));
+        }
+        let path_len = reader.u32()? as usize;
+        let path = String::from_utf8(reader.take(path_len)?.to_vec())?;
+        let mtime = reader.u128()?;
+        let size = reader.u64()?;
+        let hash = reader.u64()?;
+        let alpha = reader.u64()?;
+        let pixels = reader.f64()?;
+        let count = reader.u32()? as usize;
+        if count > reader.0.len() / 11 {
+            return Err(anyhow!("unexpected end of cache entry"));
+        }
+        let mut colors = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let color = reader.take(3)?;
+            colors.insert([color[0], color[1], color[2]], reader.f64()?);
+        }
+        Ok(Entry {
+            path,
+            mtime,
+            size,
+            hash,
+            alpha,
+            histogram: Histogram { colors, pixels },
+        })
+    }
+}
@@ -1,34 +1,76 @@
-use std::{path::Path, sync::Mutex};
+use std::sync::Mutex;
 
 use crate::{
-    colors::FloatColor,
+    colors::{self, FloatColor, Lab},
+    histogram::Histogram,
     interface::{StatusCalculating, Tui},
+    palette::Palette,
 };
-use anyhow::Result;
-use image::io::Reader as ImageReader;
-use rand::Rng;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
-pub struct ColorData(Vec<Vec<Vec<u64>>>);
+use serde::Deserialize;
+pub struct ColorData(Vec<Vec<Vec<f64>>>);
 
 impl ColorData {
     pub fn new() -> ColorData {
-        ColorData(vec![vec![vec![0u64; 256]; 256]; 256])
+        ColorData(vec![vec![vec![0f64; 256]; 256]; 256])
     }
 
-    pub fn add(&mut self, filename: &Path) -> Result<()> {
-        let img = ImageReader::open(filename)?.decode()?.to_rgb8();
-        for pixel in img.enumerate_pixels() {
-            let color = pixel.2;
-            self.0[color[0] as usize][color[1] as usize][color[2] as usize] += 1;
+    pub fn add_histogram(&mut self, histogram: &Histogram, weight: f64) {
+        for (color, count) in histogram.colors.iter() {
+            self.0[color[0] as usize][color[1] as usize][color[2] as usize] += count * weight;
         }
-        Ok(())
+    }
+
+    /// Adds every color once, for example the entries of a palette
+    pub fn add_colors(&mut self, colors: &[[u8; 3]]) {
+        for color in colors {
+            self.0[color[0] as usize][color[1] as usize][color[2] as usize] += 1.0;
+        }
+    }
+
+    pub fn colors(&self) -> Vec<([u8; 3], f64)> {
+        let mut colors = vec![];
+        for r in 0..256 {
+            for g in 0..256 {
+                for b in 0..256 {
+                    let count = self.0[r][g][b];
+                    if count > 0.0 {
+                        colors.push(([r as u8, g as u8, b as u8], count));
+                    }
+                }
+            }
+        }
+        colors
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum Algorithm {
+    /// k-means with k-means++ seeding
+    #[value(name = "kmeans++")]
+    #[serde(rename = "kmeans++")]
+    KMeansPlusPlus,
+    /// k-means with uniformly random seeding
+    #[value(name = "kmeans")]
+    #[serde(rename = "kmeans")]
+    KMeans,
+}
+
+pub struct CalcOptions {
+    pub colors: u32,
+    pub attempts: u32,
+    pub max_steps: u32,
+    pub algorithm: Algorithm,
+    pub seed: Option<u64>,
+}
+
 struct ColorPoint {
     color: FloatColor,
     segment: i32,
-    count: u64,
+    count: f64,
     distance: f64,
 }
 
@@ -43,112 +85,180 @@ impl ColorPoint {
     }
 }
 
+/// Reserved colors followed by the colors of the fixed images, without duplicates
+pub fn fixed_list(fixed_colors: &ColorData, reserved: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let mut fixed: Vec<[u8; 3]> = vec![];
+    let fixed_colors = fixed_colors.colors().into_iter().map(|(color, _)| color);
+    for color in reserved.iter().copied().chain(fixed_colors) {
+        if !fixed.contains(&color) {
+            fixed.push(color);
+        }
+    }
+    fixed
+}
+
 pub struct ColorCalc {
     points: Vec<ColorPoint>,
     centroids: Vec<FloatColor>,
+    fixed: usize,
+    /// Starting centroids of the first attempt
+    seeds: Vec<FloatColor>,
 
-    colors: i32,
-    point_count: u64,
+    attempts: usize,
+    max_steps: usize,
+    algorithm: Algorithm,
+    rng: StdRng,
 
     total_distance: f64,
     points_changed: u64,
 
+    best_centroids: Vec<FloatColor>,
+    best_population: Vec<f64>,
+    best_error: f64,
+
     pub unique_colors: u64,
     pub fixed_colors: u64,
 }
 
 impl ColorCalc {
-    pub fn new(color_count: i32, adjustable_colors: ColorData, fixed_colors: ColorData) -> ColorCalc {
-        let mut total_colors = {
-            if color_count > 256 {
-                256u64
-            } else if color_count < 1 {
-                1u64
-            } else {
-                color_count as u64
-            }
-        };
-
-        let mut points = vec![];
+    pub fn new(
+        options: &CalcOptions,
+        adjustable_colors: ColorData,
+        fixed_colors: ColorData,
+        reserved: &[[u8; 3]],
+    ) -> Result<ColorCalc> {
+        let fixed = fixed_list(&fixed_colors, reserved);
+        let points = adjustable_colors
+            .colors()
+            .into_iter()
+            .map(|(color, count)| (FloatColor::from_rgb8(color), count))
+            .collect();
+        ColorCalc::from_points(options, points, &fixed)
+    }
 
-        for r in 0..256 {
-            for g in 0..256 {
-                for b in 0..256 {
-                    if adjustable_colors.0[r][g][b] > 0 {
-                        points.push(ColorPoint {
-                            color: FloatColor::new(r as i32, g as i32, b as i32),
-                            segment: 0,
-                            count: adjustable_colors.0[r][g][b],
-                            distance: f64::MAX,
-                        })
-                    }
-                }
-            }
+    /// Clusters arbitrary weighted points, `fixed` colors are kept as the first centroids
+    pub fn from_points(options: &CalcOptions, points: Vec<(FloatColor, f64)>, fixed: &[[u8; 3]]) -> Result<ColorCalc> {
+        let total_colors = options.colors.clamp(1, 256) as usize;
+        if fixed.len() > total_colors {
+            return Err(anyhow!(
+                "{} fixed colors don't fit into a palette of {} colors",
+                fixed.len(),
+                total_colors
+            ));
         }
 
+        let points: Vec<ColorPoint> = points
+            .into_iter()
+            .map(|(color, count)| ColorPoint {
+                color,
+                segment: 0,
+                count,
+                distance: f64::MAX,
+            })
+            .collect();
+
         let unique_colors = points.len() as u64;
-        if total_colors > unique_colors {
-            total_colors = unique_colors;
-        }
+        let adjustable = (total_colors - fixed.len()).min(points.len());
 
-        ColorCalc {
-            colors: color_count,
+        let mut centroids: Vec<FloatColor> = fixed.iter().map(|&color| FloatColor::from_rgb8(color)).collect();
+        centroids.resize(fixed.len() + adjustable, FloatColor::BLACK);
+
+        let rng = match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Ok(ColorCalc {
             points,
-            centroids: vec![FloatColor::BLACK; total_colors as usize],
-            point_count: unique_colors,
+            best_centroids: centroids.clone(),
+            best_population: vec![0.0; centroids.len()],
+            centroids,
+            fixed: fixed.len(),
+            seeds: vec![],
+            attempts: options.attempts.max(1) as usize,
+            max_steps: options.max_steps.max(1) as usize,
+            algorithm: options.algorithm,
+            rng,
             total_distance: 0.0,
             points_changed: 0,
+            best_error: f64::MAX,
             unique_colors,
-            fixed_colors: 0,
-        }
+            fixed_colors: fixed.len() as u64,
+        })
     }
 
-    fn init_centroids(&mut self) {
-        let mut rng = rand::thread_rng();
-        self.points.swap(0, rng.gen_range(0..self.point_count) as usize);
-        for cent_ind in 1..(self.colors - 1) as usize {
-            let mut sum = 0.0;
-            let cent_color = self.points[cent_ind - 1].color;
-            for i in cent_ind - 1..self.point_count as usize {
-                sum += self.points[i].distance_squared(cent_color);
-            }
+    /// Starts the first attempt from `colors` instead of random points, for example from a previous palette
+    pub fn seed_centroids(&mut self, colors: &[[u8; 3]]) {
+        let fixed: Vec<[u8; 3]> = self.centroids[..self.fixed].iter().map(|c| c.to_rgb8()).collect();
+        self.seeds = colors
+            .iter()
+            .filter(|color| !fixed.contains(color))
+            .take(self.centroids.len() - self.fixed)
+            .map(|&color| FloatColor::from_rgb8(color))
+            .collect();
+    }
 
-            let rnd = sum * rng.gen::<f64>();
-            sum = 0.0;
-            let mut next = self.point_count as usize - 1;
-            for i in cent_ind + 1..self.point_count as usize {
-                sum += self.points[i].distance;
-                if sum > rnd {
-                    next = i;
-                    break;
+    fn init_centroids(&mut self, attempt: usize) {
+        for point in self.points.iter_mut() {
+            point.distance = f64::MAX;
+        }
+        for i in 0..self.fixed {
+            let cent_color = self.centroids[i];
+            self.points.par_iter_mut().for_each(|point| {
+                point.distance_squared(cent_color);
+            });
+        }
+
+        let seeds = if attempt == 0 { self.seeds.len() } else { 0 };
+        for cent_ind in self.fixed..self.fixed + seeds {
+            let cent_color = self.seeds[cent_ind - self.fixed];
+            self.centroids[cent_ind] = cent_color;
+            self.points.par_iter_mut().for_each(|point| {
+                point.distance_squared(cent_color);
+            });
+        }
+
+        for cent_ind in self.fixed + seeds..self.centroids.len() {
+            let mut next = self.rng.gen_range(0..self.points.len());
+            if self.algorithm == Algorithm::KMeansPlusPlus && cent_ind > 0 {
+                let sum: f64 = self.points.iter().map(|point| point.distance).sum();
+                let rnd = sum * self.rng.gen::<f64>();
+                let mut sum = 0.0;
+                for (i, point) in self.points.iter().enumerate() {
+                    sum += point.distance;
+                    if sum > rnd {
+                        next = i;
+                        break;
+                    }
                 }
             }
-            self.points.swap(cent_ind, next);
-        }
-        for i in 0..self.colors {
-            self.centroids[i as usize] = self.points[i as usize].color;
+            let cent_color = self.points[next].color;
+            self.centroids[cent_ind] = cent_color;
+            self.points.par_iter_mut().for_each(|point| {
+                point.distance_squared(cent_color);
+            });
         }
     }
 
     fn calc_centroids(&mut self) {
-        let mut new_centroids = vec![FloatColor::BLACK; self.colors as usize];
-        let mut counts = vec![0u64; self.colors as usize];
+        let mut new_centroids = vec![FloatColor::BLACK; self.centroids.len()];
+        let mut counts = vec![0f64; self.centroids.len()];
         for point in &self.points {
             counts[point.segment as usize] += point.count;
             let c = &mut new_centroids[point.segment as usize];
-            c.r += point.color.r * (point.count as f64);
-            c.g += point.color.g * (point.count as f64);
-            c.b += point.color.b * (point.count as f64);
+            c.r += point.color.r * point.count;
+            c.g += point.color.g * point.count;
+            c.b += point.color.b * point.count;
         }
 
         self.total_distance = 0.0;
 
-        for (i, c) in self.centroids.iter_mut().enumerate() {
-            if counts[i] == 0 {
+        for (i, c) in self.centroids.iter_mut().enumerate().skip(self.fixed) {
+            if counts[i] == 0.0 {
                 continue;
             }
 
-            let count = counts[i] as f64;
+            let count = counts[i];
             new_centroids[i].r /= count;
             new_centroids[i].g /= count;
             new_centroids[i].b /= count;
@@ -181,6 +291,21 @@ impl ColorCalc {
         self.points_changed = *points_changed.lock().unwrap();
     }
 
+    fn error(&self) -> f64 {
+        self.points
+            .par_iter()
+            .map(|point| point.color.distance_squared(self.centroids[point.segment as usize]) * point.count)
+            .sum()
+    }
+
+    fn population(&self) -> Vec<f64> {
+        let mut population = vec![0.0; self.centroids.len()];
+        for point in self.points.iter() {
+            population[point.segment as usize] += point.count;
+        }
+        population
+    }
+
     fn update_stats(
         &self,
         block: &mut StatusCalculating,
@@ -189,15 +314,11 @@ impl ColorCalc {
         step: usize,
         passed: usize,
     ) -> Result<()> {
-        let step_current;
-        let steps_total;
-        if attempt == 0 {
-            step_current = step;
-            steps_total = 5 * 1000;
-        } else {
-            step_current = passed + step;
-            steps_total = passed + passed / attempt * (5 - attempt);
-        }
+        let (step_current, steps_total) = match passed.checked_div(attempt) {
+            Some(per_attempt) => (passed + step, passed + per_attempt * (self.attempts - attempt)),
+            None => (step, self.attempts * self.max_steps),
+        };
+        let steps_total = steps_total.max(step_current);
 
         block.update(
             tui,
@@ -212,22 +333,78 @@ impl ColorCalc {
     }
 
     pub fn run(&mut self, block: &mut StatusCalculating, tui: &mut Tui) -> Result<()> {
+        if self.centroids.len() == self.fixed {
+            return Ok(());
+        }
         let mut steps_passed = 0;
-        for a in 0..5 {
-            self.init_centroids();
-            for s in 0..1000 {
+        for a in 0..self.attempts {
+            block.attempt_started(tui, a as u32)?;
+            self.init_centroids(a);
+            let mut steps = self.max_steps;
+            for s in 0..self.max_steps {
                 self.calc_segments();
                 if self.points_changed == 0 {
                     self.update_stats(block, tui, a, s, steps_passed)?;
-                    steps_passed += s;
+                    steps = s;
                     break;
                 }
                 self.calc_centroids();
-                if block.timer.needs_update() {
+                if block.needs_update(tui) {
                     self.update_stats(block, tui, a, s, steps_passed)?;
                 }
             }
+            steps_passed += steps;
+
+            let error = self.error();
+            block.attempt_finished(tui, a as u32, steps as u32, error)?;
+            if error < self.best_error {
+                self.best_error = error;
+                self.best_centroids.clone_from(&self.centroids);
+                self.best_population = self.population();
+            }
         }
         Ok(())
     }
+
+    /// Root mean square distance of the points to the best centroids, in 8-bit RGB units
+    pub fn rms_error(&self) -> f64 {
+        let total: f64 = self.points.iter().map(|point| point.count).sum();
+        if total > 0.0 && self.best_error < f64::MAX {
+            (self.best_error / total).sqrt() * 255.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Weighted mean distance of the points to their nearest best centroid, in 8-bit RGB units and CIEDE2000
+    pub fn mean_errors(&self) -> (f64, f64) {
+        let lab: Vec<Lab> = self.best_centroids.iter().map(|&color| Lab::from_rgb(color)).collect();
+        let (total, error, delta_e) = self
+            .points
+            .par_iter()
+            .map(|point| {
+                let index = colors::nearest(&self.best_centroids, point.color);
+                (
+                    point.count,
+                    point.color.distance(self.best_centroids[index]) * 255.0 * point.count,
+                    Lab::from_rgb(point.color).delta_e(lab[index]) * point.count,
+                )
+            })
+            .reduce(|| (0.0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+        if total > 0.0 {
+            (error / total, delta_e / total)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    pub fn best_centroids(&self) -> &[FloatColor] {
+        &self.best_centroids
+    }
+
+    pub fn palette(&self) -> Palette {
+        let mut palette = Palette::new(self.best_centroids.iter().map(|c| c.to_rgb8()).collect());
+        palette.population.clone_from(&self.best_population);
+        palette.lock_first(self.fixed)
+    }
 }
@@ -1,3 +1,5 @@
+use anyhow::{anyhow, Result};
+
 #[derive(Debug, Clone, Copy)]
 pub struct FloatColor {
     pub r: f64,
@@ -30,5 +32,180 @@ impl FloatColor {
         }
     }
 
+    pub fn from_rgb8(color: [u8; 3]) -> FloatColor {
+        FloatColor::new(color[0] as i32, color[1] as i32, color[2] as i32)
+    }
+
+    /// Rec. 601 luma
+    pub fn luminance(&self) -> f64 {
+        0.299 * self.r + 0.587 * self.g + 0.114 * self.b
+    }
+
+    /// Hue angle in degrees, 0 for grays
+    pub fn hue(&self) -> f64 {
+        let max = self.r.max(self.g).max(self.b);
+        let min = self.r.min(self.g).min(self.b);
+        let delta = max - min;
+        if delta <= 0.0 {
+            return 0.0;
+        }
+        let hue = if max == self.r {
+            ((self.g - self.b) / delta).rem_euclid(6.0)
+        } else if max == self.g {
+            (self.b - self.r) / delta + 2.0
+        } else {
+            (self.r - self.g) / delta + 4.0
+        };
+        hue * 60.0
+    }
+
+    /// HSV saturation
+    pub fn saturation(&self) -> f64 {
+        let max = self.r.max(self.g).max(self.b);
+        let min = self.r.min(self.g).min(self.b);
+        if max <= 0.0 {
+            0.0
+        } else {
+            (max - min) / max
+        }
+    }
+
+    /// Linear interpolation from `self` (t = 0) to `other` (t = 1)
+    pub fn mix(self, other: FloatColor, t: f64) -> FloatColor {
+        FloatColor {
+            r: self.r + (other.r - self.r) * t,
+            g: self.g + (other.g - self.g) * t,
+            b: self.b + (other.b - self.b) * t,
+        }
+    }
+
+    pub fn to_rgb8(self) -> [u8; 3] {
+        [
+            (self.r * 255.0).round().clamp(0.0, 255.0) as u8,
+            (self.g * 255.0).round().clamp(0.0, 255.0) as u8,
+            (self.b * 255.0).round().clamp(0.0, 255.0) as u8,
+        ]
+    }
+
     pub const BLACK: FloatColor = FloatColor { r: 0.0, g: 0.0, b: 0.0 };
 }
+
+/// CIE L*a*b* color, D65 white point
+#[derive(Debug, Clone, Copy)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl Lab {
+    /// Converts an sRGB color
+    pub fn from_rgb(color: FloatColor) -> Lab {
+        let linear = |c: f64| {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        let (r, g, b) = (linear(color.r), linear(color.g), linear(color.b));
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+        let f = |t: f64| {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+
+    /// CIEDE2000 color difference
+    pub fn delta_e(&self, other: Lab) -> f64 {
+        let (l1, a1, b1) = (self.l, self.a, self.b);
+        let (l2, a2, b2) = (other.l, other.a, other.b);
+        let pow7 = |v: f64| v.powi(7);
+
+        let c_mean = ((a1.hypot(b1)) + (a2.hypot(b2))) / 2.0;
+        let g = 0.5 * (1.0 - (pow7(c_mean) / (pow7(c_mean) + pow7(25.0))).sqrt());
+        let (a1p, a2p) = (a1 * (1.0 + g), a2 * (1.0 + g));
+        let (c1p, c2p) = (a1p.hypot(b1), a2p.hypot(b2));
+        let hue = |b: f64, a: f64| {
+            if a == 0.0 && b == 0.0 {
+                0.0
+            } else {
+                b.atan2(a).to_degrees().rem_euclid(360.0)
+            }
+        };
+        let (h1p, h2p) = (hue(b1, a1p), hue(b2, a2p));
+
+        let dl = l2 - l1;
+        let dc = c2p - c1p;
+        let dh = if c1p * c2p == 0.0 {
+            0.0
+        } else if (h2p - h1p).abs() <= 180.0 {
+            h2p - h1p
+        } else if h2p <= h1p {
+            h2p - h1p + 360.0
+        } else {
+            h2p - h1p - 360.0
+        };
+        let dh_big = 2.0 * (c1p * c2p).sqrt() * (dh / 2.0).to_radians().sin();
+
+        let l_mean = (l1 + l2) / 2.0;
+        let cp_mean = (c1p + c2p) / 2.0;
+        let hp_mean = if c1p * c2p == 0.0 {
+            h1p + h2p
+        } else if (h1p - h2p).abs() <= 180.0 {
+            (h1p + h2p) / 2.0
+        } else if h1p + h2p < 360.0 {
+            (h1p + h2p + 360.0) / 2.0
+        } else {
+            (h1p + h2p - 360.0) / 2.0
+        };
+
+        let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+            + 0.24 * (2.0 * hp_mean).to_radians().cos()
+            + 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+            - 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+        let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+        let r_c = 2.0 * (pow7(cp_mean) / (pow7(cp_mean) + pow7(25.0))).sqrt();
+        let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+        let s_c = 1.0 + 0.045 * cp_mean;
+        let s_h = 1.0 + 0.015 * cp_mean * t;
+        let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+        let (tl, tc, th) = (dl / s_l, dc / s_c, dh_big / s_h);
+        (tl * tl + tc * tc + th * th + r_t * tc * th).sqrt()
+    }
+}
+
+/// Index of the palette color closest to `color`
+pub fn nearest(palette: &[FloatColor], color: FloatColor) -> usize {
+    let mut best = 0;
+    let mut min_dist = f64::MAX;
+    for (i, c) in palette.iter().enumerate() {
+        let dist = color.distance_squared(*c);
+        if dist < min_dist {
+            min_dist = dist;
+            best = i;
+        }
+    }
+    best
+}
+
+pub fn parse_hex(s: &str) -> Result<[u8; 3]> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(anyhow!("expected RRGGBB color, got \"{}\"", s));
+    }
+    let value = u32::from_str_radix(hex, 16)?;
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
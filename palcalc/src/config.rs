@@ -0,0 +1,440 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    autosize::AutoColors,
+    colorcalc::{Algorithm, CalcOptions},
+    colors,
+    cycles::CyclingConfig,
+    heatmap::HeatmapConfig,
+    histogram::{AlphaOptions, Group, GroupWeights, InputFile, Normalization},
+    inputs::{self, Filter},
+    palette::{self, OutputTarget, PlaypalTarget},
+    quality::ReportTarget,
+    ramps::RampLayout,
+    sort::SortConfig,
+    tables::{BlendTarget, ColormapTarget, InverseMapTarget, TranslucencyTarget},
+};
+
+pub const DEFAULT_CONFIG: &str = "palcalc.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// File paths or glob patterns
+    pub files: Vec<String>,
+    pub weight: f64,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            files: vec![],
+            weight: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputsConfig {
+    pub adjustable: GroupConfig,
+    pub texture: GroupConfig,
+    pub fixed: GroupConfig,
+    /// Images whose colors fill the fullbright range
+    pub fullbright: GroupConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FullbrightConfig {
+    /// First fullbright index, defaults to the end of the palette
+    pub start: Option<usize>,
+    pub count: usize,
+    /// Colors always present in the fullbright range, as RRGGBB
+    #[serde(default, deserialize_with = "deserialize_colors")]
+    pub colors: Vec<[u8; 3]>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeightConfig {
+    /// File path or glob pattern
+    pub files: String,
+    pub weight: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub colors: u32,
+    /// Pick the smallest palette meeting an error target instead of using `colors`
+    pub auto_colors: Option<AutoColors>,
+    pub shades: u32,
+    pub algorithm: Algorithm,
+    pub seed: Option<u64>,
+    pub attempts: u32,
+    pub max_steps: u32,
+    pub normalize: Normalization,
+    #[serde(deserialize_with = "deserialize_colors")]
+    pub reserved: Vec<[u8; 3]>,
+    /// Palette whose entries are reserved after `reserved`
+    pub reserved_palette: Option<PathBuf>,
+    /// Palette whose entries are kept like the colors of the fixed images
+    pub fixed_palette: Option<PathBuf>,
+    /// Palette the first clustering attempt starts from
+    pub seed_palette: Option<PathBuf>,
+    pub fullbright: Option<FullbrightConfig>,
+    /// Color cycling ranges, left out of clustering
+    pub cycling: CyclingConfig,
+    /// Lay the palette out as hue ramps instead of free clustering
+    pub ramps: Option<RampLayout>,
+    /// Reorder the final palette
+    pub sort: Option<SortConfig>,
+    /// Palette whose indices the new palette keeps as far as possible
+    pub previous: Option<PathBuf>,
+    pub alpha: AlphaOptions,
+    pub inputs: InputsConfig,
+    /// Only load files matching one of these patterns
+    pub include: Vec<String>,
+    /// Skip files matching any of these patterns
+    pub exclude: Vec<String>,
+    pub weights: Vec<WeightConfig>,
+    pub outputs: Vec<OutputTarget>,
+    pub translucency: Vec<TranslucencyTarget>,
+    pub blend: Vec<BlendTarget>,
+    pub colormaps: Vec<ColormapTarget>,
+    pub playpal: Option<PlaypalTarget>,
+    pub inverse: Option<InverseMapTarget>,
+    /// Per-image quality report
+    pub report: Option<ReportTarget>,
+    /// Per-image error heatmaps
+    pub heatmaps: Option<HeatmapConfig>,
+    /// Self-contained HTML summary of the run
+    pub html: Option<PathBuf>,
+    /// Directory for cached per-file histograms
+    pub cache: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            colors: 255,
+            auto_colors: None,
+            shades: 32,
+            algorithm: Algorithm::KMeansPlusPlus,
+            seed: None,
+            attempts: 5,
+            max_steps: 1000,
+            normalize: Normalization::None,
+            reserved: vec![],
+            reserved_palette: None,
+            fixed_palette: None,
+            seed_palette: None,
+            fullbright: None,
+            cycling: CyclingConfig::default(),
+            ramps: None,
+            sort: None,
+            previous: None,
+            alpha: AlphaOptions::default(),
+            inputs: InputsConfig::default(),
+            include: vec![],
+            exclude: vec![],
+            weights: vec![],
+            outputs: vec![],
+            translucency: vec![],
+            blend: vec![],
+            colormaps: vec![],
+            playpal: None,
+            inverse: None,
+            report: None,
+            heatmaps: None,
+            html: None,
+            cache: None,
+        }
+    }
+}
+
+pub fn deserialize_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 3], D::Error> {
+    colors::parse_hex(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+pub fn deserialize_colors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; 3]>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| colors::parse_hex(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn relative_to(base: &Path, pattern: &str) -> String {
+    base.join(pattern).to_string_lossy().into_owned()
+}
+
+impl Config {
+    /// Loads the config at `path`, or `palcalc.toml` from the current directory if it exists
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG).is_file() => Path::new(DEFAULT_CONFIG),
+            None => return Ok(Config::default()),
+        };
+        let text = fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+        let mut config: Config = toml::from_str(&text).with_context(|| format!("can't parse {}", path.display()))?;
+
+        // Paths in the config are relative to the config itself
+        let base = path.parent().unwrap_or(Path::new(""));
+        for group in [
+            &mut config.inputs.adjustable,
+            &mut config.inputs.texture,
+            &mut config.inputs.fixed,
+            &mut config.inputs.fullbright,
+        ] {
+            for pattern in group.files.iter_mut() {
+                *pattern = relative_to(base, pattern);
+            }
+        }
+        for weight in config.weights.iter_mut() {
+            weight.files = relative_to(base, &weight.files);
+        }
+        for output in config.outputs.iter_mut() {
+            output.path = base.join(&output.path);
+        }
+        for table in config.translucency.iter_mut() {
+            table.path = base.join(&table.path);
+            table.preview = table.preview.as_ref().map(|preview| base.join(preview));
+        }
+        for table in config.blend.iter_mut() {
+            table.path = base.join(&table.path);
+            table.preview = table.preview.as_ref().map(|preview| base.join(preview));
+        }
+        for colormap in config.colormaps.iter_mut() {
+            colormap.path = base.join(&colormap.path);
+            colormap.preview = colormap.preview.as_ref().map(|preview| base.join(preview));
+        }
+        if let Some(playpal) = config.playpal.as_mut() {
+            playpal.path = base.join(&playpal.path);
+            playpal.preview = playpal.preview.as_ref().map(|preview| base.join(preview));
+        }
+        if let Some(inverse) = config.inverse.as_mut() {
+            inverse.path = base.join(&inverse.path);
+        }
+        if let Some(sort) = config.sort.as_mut() {
+            sort.remap = sort.remap.as_ref().map(|remap| base.join(remap));
+        }
+        if let Some(report) = config.report.as_mut() {
+            report.path = base.join(&report.path);
+        }
+        if let Some(heatmaps) = config.heatmaps.as_mut() {
+            heatmaps.dir = base.join(&heatmaps.dir);
+        }
+        for range in config.cycling.ranges.iter_mut() {
+            range.gradient = range.gradient.as_ref().map(|gradient| base.join(gradient));
+        }
+        config.cycling.preview = config.cycling.preview.as_ref().map(|preview| base.join(preview));
+        config.html = config.html.map(|html| base.join(html));
+        config.previous = config.previous.map(|previous| base.join(previous));
+        config.reserved_palette = config.reserved_palette.map(|palette| base.join(palette));
+        config.fixed_palette = config.fixed_palette.map(|palette| base.join(palette));
+        config.seed_palette = config.seed_palette.map(|palette| base.join(palette));
+        config.cache = config.cache.map(|cache| base.join(cache));
+        Ok(config)
+    }
+
+    /// Checks settings that would otherwise only fail after the palette is calculated
+    pub fn validate(&self) -> Result<()> {
+        if self.colors as usize > palette::MAX_COLORS {
+            return Err(anyhow!(
+                "a palette can't have more than {} colors, got {}",
+                palette::MAX_COLORS,
+                self.colors
+            ));
+        }
+        for output in self.outputs.iter() {
+            output.format()?;
+        }
+        if let Some(inverse) = &self.inverse {
+            inverse.check()?;
+        }
+        if let Some(report) = &self.report {
+            report.format()?;
+        }
+        if self.ramps.is_some() && self.sort.is_some() {
+            return Err(anyhow!("a ramp palette can't be sorted"));
+        }
+        if let Some(auto) = &self.auto_colors {
+            auto.check()?;
+            if self.ramps.is_some() {
+                return Err(anyhow!("a ramp palette needs a fixed number of colors"));
+            }
+        }
+        if self.previous.is_some() && (self.ramps.is_some() || self.sort.is_some()) {
+            return Err(anyhow!("a previous palette can't be combined with ramps or sorting"));
+        }
+        if self.seed_palette.is_some() && (self.ramps.is_some() || self.previous.is_some()) {
+            return Err(anyhow!(
+                "a seed palette can't be combined with ramps or a previous palette"
+            ));
+        }
+        if self.fullbright_range().is_none() && !self.inputs.fullbright.files.is_empty() {
+            return Err(anyhow!("fullbright images need a fullbright range"));
+        }
+        let mut ranges: Vec<(String, Range<usize>)> = vec![];
+        if let Some(range) = self.fullbright_range() {
+            ranges.push(("fullbright".to_string(), range));
+        }
+        for cycle in self.cycling.ranges.iter() {
+            cycle.check()?;
+            ranges.push((cycle.name(), cycle.range()));
+        }
+        // An automatic size grows to fit the ranges, `colors` is only the default until it is chosen
+        let colors = match self.auto_colors {
+            Some(_) => palette::MAX_COLORS,
+            None => self.colors as usize,
+        };
+        for (i, (name, range)) in ranges.iter().enumerate() {
+            if range.end > colors {
+                return Err(anyhow!(
+                    "{} range {}..{} doesn't fit into a palette of {} colors",
+                    name,
+                    range.start,
+                    range.end,
+                    colors
+                ));
+            }
+            if let Some((other, _)) = ranges[..i]
+                .iter()
+                .find(|(_, other)| range.start < other.end && other.start < range.end)
+            {
+                return Err(anyhow!("{} range overlaps {} range", name, other));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fullbright_range(&self) -> Option<Range<usize>> {
+        let fullbright = self.fullbright.as_ref()?;
+        let start = fullbright
+            .start
+            .unwrap_or((self.colors as usize).saturating_sub(fullbright.count));
+        Some(start..start + fullbright.count)
+    }
+
+    /// Palette entries taken by the fullbright and cycle ranges
+    pub fn range_entries(&self) -> usize {
+        let fullbright = self.fullbright.as_ref().map_or(0, |fullbright| fullbright.count);
+        let cycles: usize = self.cycling.ranges.iter().map(|range| range.length).sum();
+        fullbright + cycles
+    }
+
+    /// Options for the lit colors, which leave out the fullbright and cycle ranges
+    pub fn calc_options(&self) -> CalcOptions {
+        CalcOptions {
+            colors: self.colors.saturating_sub(self.range_entries() as u32),
+            attempts: self.attempts,
+            max_steps: self.max_steps,
+            algorithm: self.algorithm,
+            seed: self.seed,
+        }
+    }
+
+    /// Options for clustering the fullbright images into the fullbright range
+    pub fn fullbright_calc_options(&self) -> Option<CalcOptions> {
+        let range = self.fullbright_range()?;
+        Some(CalcOptions {
+            colors: range.len() as u32,
+            ..self.calc_options()
+        })
+    }
+
+    /// Settings that shape the palette, as shown in reports
+    pub fn parameters(&self) -> Vec<(String, String)> {
+        let mut parameters = vec![
+            ("Colors", self.colors.to_string()),
+            ("Color space", "RGB".to_string()),
+            ("Algorithm", format!("{:?}", self.algorithm)),
+            ("Seed", self.seed.map_or("random".to_string(), |seed| seed.to_string())),
+            ("Attempts", self.attempts.to_string()),
+            ("Max steps", self.max_steps.to_string()),
+            ("Normalization", format!("{:?}", self.normalize)),
+            ("Shades", self.shades.to_string()),
+            ("Reserved colors", self.reserved.len().to_string()),
+        ];
+        if let Some(auto) = &self.auto_colors {
+            parameters.push(("Automatic size", auto.describe()));
+        }
+        if let Some(range) = self.fullbright_range() {
+            parameters.push(("Fullbright", format!("{}-{}", range.start, range.end.saturating_sub(1))));
+        }
+        if let Some(layout) = &self.ramps {
+            parameters.push(("Ramps", format!("{}x{}", layout.rows, layout.length)));
+        }
+        if let Some(sort) = &self.sort {
+            parameters.push(("Sort", format!("{:?}", sort.order)));
+        }
+        if let Some(previous) = &self.previous {
+            parameters.push(("Previous palette", previous.display().to_string()));
+        }
+        for (name, palette) in [
+            ("Reserved palette", &self.reserved_palette),
+            ("Fixed palette", &self.fixed_palette),
+            ("Seed palette", &self.seed_palette),
+        ] {
+            if let Some(palette) = palette {
+                parameters.push((name, palette.display().to_string()));
+            }
+        }
+        parameters
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    pub fn group_weights(&self) -> GroupWeights {
+        GroupWeights {
+            main: self.inputs.adjustable.weight,
+            texture: self.inputs.texture.weight,
+        }
+    }
+
+    fn file_weight(&self, path: &Path) -> Result<f64> {
+        let mut weight = 1.0;
+        for entry in self.weights.iter() {
+            if glob::Pattern::new(&entry.files)?.matches_path(path) {
+                weight *= entry.weight;
+            }
+        }
+        Ok(weight)
+    }
+
+    pub fn input_files(&self) -> Result<Vec<InputFile>> {
+        let groups = [
+            (&self.inputs.adjustable, Group::Main),
+            (&self.inputs.texture, Group::Texture),
+            (&self.inputs.fixed, Group::Fixed),
+            (&self.inputs.fullbright, Group::Fullbright),
+        ];
+        let filter = Filter::new(&self.include, &self.exclude)?;
+        let mut files: Vec<InputFile> = vec![];
+        for (group_config, group) in groups {
+            for pattern in group_config.files.iter() {
+                for path in inputs::expand(pattern, &filter)? {
+                    if files.iter().any(|file| file.path == path) {
+                        continue;
+                    }
+                    files.push(InputFile {
+                        weight: self.file_weight(&path)?,
+                        path,
+                        group,
+                    });
+                }
+            }
+        }
+        Ok(files)
+    }
+}
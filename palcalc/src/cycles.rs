@@ -0,0 +1,214 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame, Rgba, RgbaImage,
+};
+use serde::Deserialize;
+
+use crate::{
+    colors::{self, FloatColor},
+    config::deserialize_colors,
+    inputs,
+    palette::Palette,
+};
+
+/// Upper bound for the number of frames in the preview animation
+const MAX_PREVIEW_FRAMES: usize = 256;
+
+/// Palette range filled with a gradient whose colors rotate at runtime
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CycleRange {
+    pub name: Option<String>,
+    pub start: usize,
+    pub length: usize,
+    /// Keyframe colors spread evenly over the range, as RRGGBB
+    #[serde(default, deserialize_with = "deserialize_colors")]
+    pub colors: Vec<[u8; 3]>,
+    /// Image sampled along its longer axis, used instead of `colors`
+    pub gradient: Option<PathBuf>,
+    /// Rotate towards lower indices in the preview
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+fn default_delay() -> u32 {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CyclingConfig {
+    pub ranges: Vec<CycleRange>,
+    /// Animated GIF showing the palette while cycling
+    pub preview: Option<PathBuf>,
+    /// Preview frame delay in milliseconds
+    pub delay: u32,
+}
+
+impl Default for CyclingConfig {
+    fn default() -> Self {
+        CyclingConfig {
+            ranges: vec![],
+            preview: None,
+            delay: default_delay(),
+        }
+    }
+}
+
+/// Colors along the middle row or column of an image, whichever is longer
+fn gradient_colors(path: &Path) -> Result<Vec<[u8; 3]>> {
+    let img = image::open(path)?.into_rgb8();
+    let (width, height) = img.dimensions();
+    Ok(if width >= height {
+        (0..width).map(|x| img.get_pixel(x, height / 2).0).collect()
+    } else {
+        (0..height).map(|y| img.get_pixel(width / 2, y).0).collect()
+    })
+}
+
+/// Resamples `keyframes` to `length` evenly interpolated colors
+fn interpolate(keyframes: &[[u8; 3]], length: usize) -> Vec<[u8; 3]> {
+    let keyframes: Vec<FloatColor> = keyframes.iter().map(|&color| FloatColor::from_rgb8(color)).collect();
+    let last = keyframes.len() - 1;
+    (0..length)
+        .map(|i| {
+            let position = i as f64 * last as f64 / (length - 1).max(1) as f64;
+            let key = (position.floor() as usize).min(last);
+            let next = (key + 1).min(last);
+            keyframes[key].mix(keyframes[next], position - key as f64).to_rgb8()
+        })
+        .collect()
+}
+
+impl CycleRange {
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("cycle{}", self.start),
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.length
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.length == 0 {
+            return Err(anyhow!("cycle range {} is empty", self.name()));
+        }
+        if self.colors.is_empty() && self.gradient.is_none() {
+            return Err(anyhow!("cycle range {} needs colors or a gradient image", self.name()));
+        }
+        Ok(())
+    }
+
+    pub fn colors(&self) -> Result<Vec<[u8; 3]>> {
+        let keyframes = match &self.gradient {
+            Some(path) => gradient_colors(path)?,
+            None => self.colors.clone(),
+        };
+        if keyframes.is_empty() {
+            return Err(anyhow!("cycle range {} has no colors", self.name()));
+        }
+        Ok(interpolate(&keyframes, self.length))
+    }
+
+    /// Locked palette block holding the interpolated colors, to be inserted at `start`
+    pub fn palette(&self) -> Result<Palette> {
+        let mut palette = Palette::new(self.colors()?).lock_first(self.length);
+        palette.cycles.push((self.name(), 0..self.length));
+        Ok(palette)
+    }
+}
+
+/// Parses START+LENGTH=COLORS, where COLORS is a gradient image or comma separated RRGGBB keyframes
+pub fn parse_cycle(s: &str) -> Result<CycleRange> {
+    let (range, source) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected START+LENGTH=COLORS, got \"{}\"", s))?;
+    let range = parse_range(range)?;
+    let path = PathBuf::from(source);
+    let (colors, gradient) = if inputs::is_supported_image(&path) {
+        (vec![], Some(path))
+    } else {
+        let colors = source.split(',').map(colors::parse_hex).collect::<Result<_>>()?;
+        (colors, None)
+    };
+    Ok(CycleRange {
+        name: None,
+        start: range.start,
+        length: range.len(),
+        colors,
+        gradient,
+        reverse: false,
+    })
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Parses START+LENGTH as an index range
+pub fn parse_range(s: &str) -> Result<Range<usize>> {
+    let (start, length) = s
+        .split_once('+')
+        .ok_or_else(|| anyhow!("expected START+LENGTH, got \"{}\"", s))?;
+    let start: usize = start.parse()?;
+    Ok(start..start + length.parse::<usize>()?)
+}
+
+impl CyclingConfig {
+    /// Writes an animated preview of the configured ranges
+    pub fn write_preview(&self, palette: &Palette, path: &Path) -> Result<usize> {
+        let ranges: Vec<_> = self.ranges.iter().map(|range| (range.range(), range.reverse)).collect();
+        write_preview(palette, &ranges, self.delay, path)
+    }
+}
+
+/// Writes an animated 16x16 grid of the palette with every range rotated one step per frame,
+/// towards lower indices if its flag is set. The animation covers the common period of all ranges, cut
+/// at [`MAX_PREVIEW_FRAMES`] if that is longer.
+pub fn write_preview(palette: &Palette, ranges: &[(Range<usize>, bool)], delay: u32, path: &Path) -> Result<usize> {
+    const CELL: u32 = 16;
+    // One full period of every range together; only capped once so that ranges whose common period fits
+    // still loop without a jump
+    let period = ranges.iter().fold(1usize, |frames, (range, _)| {
+        let length = range.len().max(1);
+        (frames / gcd(frames, length)).saturating_mul(length)
+    });
+    let frames = period.min(MAX_PREVIEW_FRAMES);
+
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    for frame in 0..frames {
+        let mut colors: Vec<[u8; 3]> = (0..256).map(|i| palette.color(i)).collect();
+        for (range, reverse) in ranges.iter() {
+            let end = range.end.min(colors.len());
+            let slice = &mut colors[range.start.min(end)..end];
+            let step = frame % slice.len().max(1);
+            if *reverse {
+                slice.rotate_left(step);
+            } else {
+                slice.rotate_right(step);
+            }
+        }
+        let img = RgbaImage::from_fn(16 * CELL, 16 * CELL, |x, y| {
+            let [r, g, b] = colors[(y / CELL * 16 + x / CELL) as usize];
+            Rgba([r, g, b, 255])
+        });
+        encoder.encode_frame(Frame::from_parts(img, 0, 0, Delay::from_numer_denom_ms(delay, 1)))?;
+    }
+    Ok(frames)
+}
@@ -0,0 +1,135 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use image::{Rgb, RgbImage};
+use serde::Deserialize;
+
+use crate::{colors::FloatColor, histogram::InputFile, inputs};
+
+/// Height of the color scale drawn below the image
+const LEGEND_HEIGHT: u32 = 20;
+/// Narrowest heatmap, so the legend labels always fit
+const MIN_WIDTH: u32 = 96;
+
+/// False color stops from no error to `scale` and above
+const STOPS: [[u8; 3]; 6] = [
+    [0, 0, 0],
+    [0, 0, 255],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 255, 0],
+    [255, 0, 0],
+];
+
+/// 3x5 pixel digits, one row per byte, bit 2 is the left column
+const DIGITS: [[u8; 5]; 10] = [
+    [7, 5, 5, 5, 7],
+    [2, 6, 2, 2, 7],
+    [7, 1, 7, 4, 7],
+    [7, 1, 3, 1, 7],
+    [5, 5, 7, 1, 1],
+    [7, 4, 7, 1, 7],
+    [7, 4, 7, 5, 7],
+    [7, 1, 2, 2, 2],
+    [7, 5, 7, 5, 7],
+    [7, 5, 7, 1, 7],
+];
+
+fn default_scale() -> f64 {
+    64.0
+}
+
+/// False-colored images of the per-pixel distance to the assigned palette color
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeatmapConfig {
+    pub dir: PathBuf,
+    /// Error in 8-bit RGB units shown with the hottest color
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+fn heat_color(t: f64) -> [u8; 3] {
+    let position = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let stop = (position.floor() as usize).min(STOPS.len() - 2);
+    FloatColor::from_rgb8(STOPS[stop])
+        .mix(FloatColor::from_rgb8(STOPS[stop + 1]), position - stop as f64)
+        .to_rgb8()
+}
+
+fn draw_number(img: &mut RgbImage, x: u32, y: u32, value: u32) {
+    for (i, digit) in value.to_string().bytes().enumerate() {
+        for (row, bits) in DIGITS[(digit - b'0') as usize].iter().enumerate() {
+            for column in 0..3 {
+                let (px, py) = (x + i as u32 * 4 + column, y + row as u32);
+                if bits & (4 >> column) != 0 && px < img.width() && py < img.height() {
+                    img.put_pixel(px, py, Rgb([255, 255, 255]));
+                }
+            }
+        }
+    }
+}
+
+impl HeatmapConfig {
+    pub fn new(dir: PathBuf) -> HeatmapConfig {
+        HeatmapConfig {
+            dir,
+            scale: default_scale(),
+        }
+    }
+
+    /// One heatmap path per input file, named after the file and numbered if names repeat
+    pub fn paths(&self, files: &[InputFile]) -> Vec<PathBuf> {
+        inputs::output_paths(files.iter().map(|file| file.path.as_path()), &self.dir, ".heat.png")
+    }
+
+    /// Writes the heatmap of an image. `errors` holds one value per pixel, `None` for skipped
+    /// pixels, which are drawn as dimmed gray from `luma`.
+    pub fn write(&self, path: &Path, width: u32, height: u32, errors: &[Option<f64>], luma: &[f64]) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("can't create heatmap directory {}", self.dir.display()))?;
+        let canvas_width = width.max(MIN_WIDTH);
+        let mut img = RgbImage::new(canvas_width, height + LEGEND_HEIGHT);
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize;
+                let color = match errors[i] {
+                    Some(error) => heat_color(error / self.scale),
+                    None => [(luma[i] / 4.0) as u8; 3],
+                };
+                img.put_pixel(x, y, Rgb(color));
+            }
+        }
+
+        // Color scale with labels at zero, half and full scale
+        let bar_top = height + 2;
+        for x in 0..canvas_width {
+            let color = heat_color(x as f64 / (canvas_width - 1) as f64);
+            for y in bar_top..bar_top + 8 {
+                img.put_pixel(x, y, Rgb(color));
+            }
+        }
+        let label_y = bar_top + 10;
+        draw_number(&mut img, 0, label_y, 0);
+        let half = (self.scale / 2.0).round() as u32;
+        draw_number(
+            &mut img,
+            canvas_width / 2 - half.to_string().len() as u32 * 2,
+            label_y,
+            half,
+        );
+        let full = self.scale.round() as u32;
+        draw_number(
+            &mut img,
+            canvas_width - full.to_string().len() as u32 * 4,
+            label_y,
+            full,
+        );
+
+        img.save(path)?;
+        Ok(())
+    }
+}
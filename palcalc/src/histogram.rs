@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::{io::Reader as ImageReader, DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::{colorcalc::ColorData, config::deserialize_colors, inputs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Group {
+    Main,
+    Texture,
+    Fixed,
+    Fullbright,
+}
+
+impl Group {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Group::Main => "main",
+            Group::Texture => "texture",
+            Group::Fixed => "fixed",
+            Group::Fullbright => "fullbright",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Every pixel counts once
+    None,
+    /// Every image contributes the same total weight
+    Pixels,
+    /// Every group contributes the same total weight
+    Group,
+}
+
+pub struct InputFile {
+    pub path: PathBuf,
+    pub group: Group,
+    pub weight: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlphaOptions {
+    /// Pixels with alpha below this value are skipped
+    pub threshold: u8,
+    /// Count partially transparent pixels proportionally to their alpha
+    pub weighted: bool,
+    /// Colors treated as fully transparent
+    #[serde(deserialize_with = "deserialize_colors")]
+    pub color_keys: Vec<[u8; 3]>,
+}
+
+impl Default for AlphaOptions {
+    fn default() -> Self {
+        AlphaOptions {
+            threshold: 1,
+            weighted: false,
+            color_keys: vec![],
+        }
+    }
+}
+
+impl AlphaOptions {
+    /// Weight of a pixel in the histogram, 0 for pixels that are skipped
+    pub fn pixel_weight(&self, pixel: [u8; 4]) -> f64 {
+        let alpha = pixel[3];
+        if alpha < self.threshold || self.color_keys.contains(&[pixel[0], pixel[1], pixel[2]]) {
+            0.0
+        } else if self.weighted {
+            alpha as f64 / 255.0
+        } else {
+            1.0
+        }
+    }
+}
+
+pub struct Histogram {
+    pub colors: HashMap<[u8; 3], f64>,
+    pub pixels: f64,
+}
+
+impl Histogram {
+    pub fn load(filename: &Path, alpha: &AlphaOptions) -> Result<Histogram> {
+        Ok(Histogram::from_image(inputs::open_image(filename)?, alpha))
+    }
+
+    /// Decodes an image file that is already read into memory
+    pub fn decode(filename: &Path, data: &[u8], alpha: &AlphaOptions) -> Result<Histogram> {
+        let mut reader = ImageReader::new(Cursor::new(data));
+        match ImageFormat::from_path(filename) {
+            Ok(format) => reader.set_format(format),
+            Err(_) => reader = reader.with_guessed_format()?,
+        }
+        Ok(Histogram::from_image(reader.decode()?, alpha))
+    }
+
+    pub fn from_image(img: DynamicImage, alpha: &AlphaOptions) -> Histogram {
+        let img = img.to_rgba8();
+        let mut colors = HashMap::new();
+        let mut pixels = 0.0;
+        for pixel in img.pixels() {
+            let weight = alpha.pixel_weight(pixel.0);
+            if weight > 0.0 {
+                *colors.entry([pixel[0], pixel[1], pixel[2]]).or_insert(0.0) += weight;
+                pixels += weight;
+            }
+        }
+        Histogram { colors, pixels }
+    }
+}
+
+pub struct GroupWeights {
+    pub main: f64,
+    pub texture: f64,
+}
+
+impl GroupWeights {
+    fn get(&self, group: Group) -> f64 {
+        match group {
+            Group::Main => self.main,
+            Group::Texture => self.texture,
+            Group::Fixed | Group::Fullbright => 1.0,
+        }
+    }
+}
+
+pub fn parse_weight(s: &str) -> Result<(String, f64)> {
+    let (pattern, weight) = s
+        .rsplit_once('=')
+        .ok_or_else(|| anyhow!("expected FILE=WEIGHT, got \"{}\"", s))?;
+    Ok((pattern.to_string(), weight.parse()?))
+}
+
+pub fn merge(
+    histograms: &[(InputFile, Histogram)],
+    normalization: Normalization,
+    group_weights: &GroupWeights,
+    target: &mut ColorData,
+    group: Group,
+) {
+    let group_pixels: f64 = histograms
+        .iter()
+        .filter(|(file, _)| file.group == group)
+        .map(|(_, hist)| hist.pixels)
+        .sum();
+
+    for (file, hist) in histograms.iter().filter(|(file, _)| file.group == group) {
+        let norm = match normalization {
+            Normalization::None => 1.0,
+            Normalization::Pixels => 1.0 / hist.pixels,
+            Normalization::Group => 1.0 / group_pixels,
+        };
+        if !norm.is_finite() {
+            continue;
+        }
+        target.add_histogram(hist, file.weight * group_weights.get(file.group) * norm);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt::Write as _, fs, io::Cursor, path::Path, time::Duration};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{ImageFormat, RgbImage};
+
+use crate::{palette::Palette, quality::ImageQuality};
+
+/// Largest side of the before and after thumbnails
+pub const THUMBNAIL_SIZE: u32 = 160;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; background: #f4f4f4; color: #222; }
+h2 { border-bottom: 1px solid #aaa; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { padding: 3px 8px; border: 1px solid #ccc; text-align: right; }
+th { background: #ddd; }
+td.name { text-align: left; }
+.palette td { width: 56px; height: 40px; font-size: 10px; text-align: center; font-family: monospace; }
+.palette td.locked { outline: 2px solid #000; outline-offset: -4px; }
+.palette td.fullbright { outline: 2px dashed #fc0; outline-offset: -4px; }
+.palette td.cycle { outline: 2px dotted #0cf; outline-offset: -4px; }
+img { image-rendering: pixelated; vertical-align: middle; }
+.thumbs img { margin-right: 4px; background: repeating-conic-gradient(#ccc 0 25%, #fff 0 50%) 0 0 / 16px 16px; }
+";
+
+/// Collects the results of a run and writes them as one HTML file with embedded images
+#[derive(Default)]
+pub struct HtmlReport {
+    pub parameters: Vec<(String, String)>,
+    pub phases: Vec<(String, Duration)>,
+    /// Rendered colormaps and blend tables
+    pub tables: Vec<(String, RgbImage)>,
+    pub images: Vec<ImageQuality>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn png_uri(data: &[u8]) -> String {
+    format!("data:image/png;base64,{}", STANDARD.encode(data))
+}
+
+fn image_uri(img: &RgbImage) -> Result<String> {
+    let mut data = Cursor::new(vec![]);
+    img.write_to(&mut data, ImageFormat::Png)?;
+    Ok(png_uri(&data.into_inner()))
+}
+
+impl HtmlReport {
+    fn palette_grid(&self, html: &mut String, palette: &Palette) -> Result<()> {
+        let total: f64 = palette.population.iter().sum();
+        writeln!(html, "<h2>Palette</h2>\n<table class=\"palette\">")?;
+        for row in 0..palette.colors.len().div_ceil(16) {
+            html.push_str("<tr>");
+            for index in row * 16..(row * 16 + 16).min(palette.colors.len()) {
+                let [r, g, b] = palette.colors[index];
+                let class = if palette.locked[index] {
+                    " class=\"locked\""
+                } else if palette.is_fullbright(index) {
+                    " class=\"fullbright\""
+                } else if palette.is_cycling(index) {
+                    " class=\"cycle\""
+                } else {
+                    ""
+                };
+                let luma = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
+                let text = if luma > 128.0 { "#000" } else { "#fff" };
+                let share = if total > 0.0 {
+                    format!("{:.2}%", palette.population[index] * 100.0 / total)
+                } else {
+                    String::new()
+                };
+                write!(
+                    html,
+                    "<td{} style=\"background:#{:02x}{:02x}{:02x};color:{}\" title=\"index {}\">{}<br>{:02x}{:02x}{:02x}<br>{}</td>",
+                    class, r, g, b, text, index, index, r, g, b, share
+                )?;
+            }
+            html.push_str("</tr>\n");
+        }
+        writeln!(
+            html,
+            "</table>\n<p>Solid outline: reserved or fixed, dashed: fullbright, dotted: color cycling. \
+             Percentages are the share of weighted pixels assigned to each entry.</p>"
+        )?;
+        for (name, range) in palette.cycles.iter() {
+            writeln!(html, "<p>Cycle {}: {}-{}</p>", escape(name), range.start, range.end - 1)?;
+        }
+        Ok(())
+    }
+
+    fn quality_table(&self, html: &mut String) -> Result<()> {
+        writeln!(
+            html,
+            "<h2>Images</h2>\n<table>\n<tr><th>File</th><th>Before / after</th><th>MSE</th><th>PSNR</th>\
+             <th>Max</th><th>Above threshold</th><th>&Delta;E2000</th><th>SSIM</th></tr>"
+        )?;
+        for image in self.images.iter() {
+            let thumbs = match &image.thumbnails {
+                Some(thumbnails) => format!(
+                    "<img src=\"{}\"><img src=\"{}\">",
+                    png_uri(&thumbnails.original),
+                    png_uri(&thumbnails.remapped)
+                ),
+                None => String::new(),
+            };
+            writeln!(
+                html,
+                "<tr><td class=\"name\">{}</td><td class=\"thumbs\">{}</td><td>{:.2}</td><td>{:.2}</td>\
+                 <td>{:.2}</td><td>{:.1}%</td><td>{:.2}</td><td>{:.4}</td></tr>",
+                escape(&image.path.to_string_lossy()),
+                thumbs,
+                image.mse,
+                image.psnr,
+                image.max_error,
+                image.above_threshold,
+                image.delta_e,
+                image.ssim
+            )?;
+        }
+        html.push_str("</table>\n");
+        Ok(())
+    }
+
+    pub fn write(&self, palette: &Palette, path: &Path) -> Result<()> {
+        let mut html = String::new();
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>palcalc report</title>\n\
+             <style>{}</style>\n</head>\n<body>\n<h1>palcalc report</h1>",
+            STYLE
+        )?;
+
+        writeln!(html, "<h2>Parameters</h2>\n<table>")?;
+        for (name, value) in self.parameters.iter() {
+            writeln!(
+                html,
+                "<tr><th>{}</th><td class=\"name\">{}</td></tr>",
+                escape(name),
+                escape(value)
+            )?;
+        }
+        html.push_str("</table>\n");
+
+        writeln!(html, "<h2>Timing</h2>\n<table>")?;
+        for (name, duration) in self.phases.iter() {
+            writeln!(
+                html,
+                "<tr><th>{}</th><td>{:.2} s</td></tr>",
+                escape(name),
+                duration.as_secs_f64()
+            )?;
+        }
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        writeln!(
+            html,
+            "<tr><th>Total</th><td>{:.2} s</td></tr>\n</table>",
+            total.as_secs_f64()
+        )?;
+
+        self.palette_grid(&mut html, palette)?;
+
+        if !self.tables.is_empty() {
+            writeln!(html, "<h2>Shade and blend tables</h2>")?;
+            for (name, img) in self.tables.iter() {
+                writeln!(html, "<h3>{}</h3>\n<img src=\"{}\">", escape(name), image_uri(img)?)?;
+            }
+        }
+
+        if !self.images.is_empty() {
+            self.quality_table(&mut html)?;
+        }
+        html.push_str("</body>\n</html>\n");
+        fs::write(path, html)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use image::{io::Reader as ImageReader, DynamicImage, ImageFormat};
+use walkdir::WalkDir;
+
+use crate::archive;
+
+pub fn is_supported_image(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Opens an image file or an image lump addressed as ARCHIVE:NAME
+pub fn open_image(path: &Path) -> Result<DynamicImage> {
+    match archive::split(path) {
+        Some((archive, name)) => archive::open_image(&archive, &name),
+        None => Ok(ImageReader::open(path)?.decode()?),
+    }
+}
+
+/// Include and exclude glob patterns matched against the full file path
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Filter> {
+        let compile = |patterns: &[String]| -> Result<Vec<Pattern>> {
+            Ok(patterns.iter().map(|p| Pattern::new(p)).collect::<Result<_, _>>()?)
+        };
+        Ok(Filter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches_path(path)))
+            && !self.exclude.iter().any(|p| p.matches_path(path))
+    }
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() && is_supported_image(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(())
+}
+
+/// Expands a file path, directory or glob pattern into a list of image files.
+/// Directories are walked recursively and only supported image files are kept.
+/// Archives expand to their image lumps, ARCHIVE:PATTERN to the lumps matching the pattern.
+pub fn expand(pattern: &str, filter: &Filter) -> Result<Vec<PathBuf>> {
+    let path = PathBuf::from(pattern);
+    let mut files = vec![];
+    if let Some((archive, lumps)) = archive::split(&path) {
+        files = archive::expand(&archive, Some(&lumps))?;
+        if files.is_empty() {
+            return Err(anyhow!("no lumps match \"{}\"", pattern));
+        }
+    } else if path.is_dir() {
+        walk_dir(&path, &mut files)?;
+    } else if path.is_file() && archive::is_archive(&path) {
+        files = archive::expand(&path, None)?;
+    } else if path.exists() {
+        files.push(path);
+    } else {
+        let paths = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+        if paths.is_empty() {
+            return Err(anyhow!("no files match \"{}\"", pattern));
+        }
+        for path in paths {
+            if path.is_dir() {
+                walk_dir(&path, &mut files)?;
+            } else if archive::is_archive(&path) {
+                files.extend(archive::expand(&path, None)?);
+            } else if is_supported_image(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.retain(|path| filter.matches(path));
+    Ok(files)
+}
+
+/// One output path in `dir` per input path, named after the file with `suffix` and numbered if names repeat
+pub fn output_paths<'a>(inputs: impl IntoIterator<Item = &'a Path>, dir: &Path, suffix: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for input in inputs {
+        // Lumps are named after the lump, which may be a path inside a PAK
+        let lump = archive::split(input).map(|(_, name)| PathBuf::from(name));
+        let stem = lump
+            .as_deref()
+            .unwrap_or(input)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let mut path = dir.join(format!("{}{}", stem, suffix));
+        let mut n = 2;
+        while paths.contains(&path) {
+            path = dir.join(format!("{}-{}{}", stem, n, suffix));
+            n += 1;
+        }
+        paths.push(path);
+    }
+    paths
+}
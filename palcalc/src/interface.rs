@@ -1,34 +1,123 @@
 use anyhow::Result;
+use clap::ValueEnum;
 use crossterm::{
-    cursor, execute, queue,
+    cursor,
+    event::{self, Event as TerminalEvent, KeyEventKind},
+    execute, queue,
     style::{self, Color},
     terminal::{self, ClearType},
 };
+use serde::Serialize;
 use std::{
-    io::{stdout, Stdout, Write},
+    io::{self, stdout, Write},
     time::{Duration, Instant},
 };
 
+/// Width the invisible screen is laid out with when progress is reported as JSON
+const JSON_WIDTH: u16 = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ProgressFormat {
+    /// Full screen terminal interface
+    #[default]
+    Tui,
+    /// Newline-delimited JSON events on stdout
+    Json,
+}
+
+/// Progress event written as one JSON line in `ProgressFormat::Json`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    FileLoaded {
+        file: &'a str,
+        progress: u32,
+        total: u32,
+        elapsed: f64,
+    },
+    LoadingFinished {
+        files: u32,
+        elapsed: f64,
+    },
+    AttemptStarted {
+        stage: &'a str,
+        attempt: u32,
+        attempts: u32,
+    },
+    Step {
+        stage: &'a str,
+        attempt: u32,
+        step: u32,
+        points_changed: u64,
+        total_distance: f64,
+        progress: u32,
+        total: u32,
+        elapsed: f64,
+    },
+    AttemptFinished {
+        stage: &'a str,
+        attempt: u32,
+        steps: u32,
+        error: f64,
+    },
+    OutputWritten {
+        path: &'a str,
+        message: &'a str,
+    },
+}
+
+/// Writes `event` as one JSON line on stdout
+pub fn write_event(event: &Event) -> Result<()> {
+    let mut out = stdout().lock();
+    serde_json::to_writer(&mut out, event)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
 pub struct Tui {
-    out: Stdout,
+    out: Box<dyn Write>,
     pub width: u16,
     offset: u16,
+    format: ProgressFormat,
 }
 
 impl Tui {
-    pub fn new() -> Result<Tui> {
+    pub fn new(format: ProgressFormat) -> Result<Tui> {
+        if format == ProgressFormat::Json {
+            // Everything is still drawn, just into nowhere, so the stream only carries events
+            return Ok(Tui {
+                out: Box::new(io::sink()),
+                width: JSON_WIDTH,
+                offset: 0,
+                format,
+            });
+        }
         let width = terminal::size()?.0;
         terminal::enable_raw_mode()?;
         let mut stdout = stdout();
 
         execute!(stdout, terminal::EnterAlternateScreen, terminal::Clear(ClearType::All))?;
         Ok(Tui {
-            out: stdout,
+            out: Box::new(stdout),
             width,
             offset: 0,
+            format,
         })
     }
 
+    pub fn is_json(&self) -> bool {
+        self.format == ProgressFormat::Json
+    }
+
+    /// Writes `event` as a JSON line, does nothing on the terminal interface
+    pub fn event(&mut self, event: &Event) -> Result<()> {
+        if self.is_json() {
+            write_event(event)?;
+        }
+        Ok(())
+    }
+
     pub fn show_logo(&mut self) -> Result<()> {
         let offset = (self.width - 63) / 2;
         execute!(
@@ -85,10 +174,35 @@ impl Tui {
         self.out.flush()?;
         Ok(())
     }
+
+    /// Keeps the screen up until a key is pressed, so results drawn last can be read before the
+    /// alternate screen is left. Returns right away with JSON progress.
+    pub fn wait_for_key(&mut self) -> Result<()> {
+        if self.is_json() {
+            return Ok(());
+        }
+        let bottom = terminal::size()?.1.saturating_sub(1);
+        execute!(
+            self.out,
+            style::ResetColor,
+            cursor::MoveTo(2, bottom),
+            style::Print("Press any key to exit")
+        )?;
+        loop {
+            if let TerminalEvent::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    return Ok(());
+                }
+            }
+        }
+    }
 }
 
 impl Drop for Tui {
     fn drop(&mut self) {
+        if self.is_json() {
+            return;
+        }
         execute!(self.out, cursor::Show, terminal::LeaveAlternateScreen).unwrap();
         terminal::disable_raw_mode().unwrap();
     }
@@ -270,6 +384,10 @@ impl Timer {
         self.last_update = Instant::now();
     }
 
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
     pub fn get_elapsed(&self) -> String {
         duration_format(self.start.elapsed())
     }
@@ -297,19 +415,37 @@ pub struct StatusImageLoading {
 }
 
 impl StatusImageLoading {
-    pub fn new(tui: &mut Tui, total_files: u32) -> Result<StatusImageLoading> {
-        tui.prepare_block("Loading images", tui.offset, 6)?;
+    /// `groups` are file counts per input group, shown before loading starts
+    pub fn new(tui: &mut Tui, groups: &[(&str, u32)]) -> Result<StatusImageLoading> {
+        let total_files = groups.iter().map(|(_, count)| count).sum();
+        tui.prepare_block("Loading images", tui.offset, 9)?;
         let mut counter = RightCounter::new(0, 4, total_files);
         counter.x = tui.width - counter.get_width() - 2;
-        execute!(
+        queue!(
             tui.out,
             style::SetBackgroundColor(Color::Grey),
             style::SetForegroundColor(Color::Black),
             cursor::MoveTo(2, 2 + tui.offset),
             style::Print("Elapsed:"),
             cursor::MoveTo(tui.width / 2, 2 + tui.offset),
-            style::Print("Remaining:")
+            style::Print("Remaining:"),
+            cursor::MoveTo(2, 7 + tui.offset),
+            style::Print("Files"),
         )?;
+        let column = (tui.width - 4) / groups.len().max(1) as u16;
+        for (i, (name, count)) in groups.iter().enumerate() {
+            let x = 2 + column * i as u16;
+            queue!(
+                tui.out,
+                style::SetForegroundColor(Color::Black),
+                cursor::MoveTo(x, 8 + tui.offset),
+                style::Print(format!("{}:", name)),
+                style::SetForegroundColor(Color::Red),
+                cursor::MoveTo(x + name.len() as u16 + 2, 8 + tui.offset),
+                style::Print(count.to_string()),
+            )?;
+        }
+        tui.refresh()?;
         Ok(StatusImageLoading {
             l_filename: Label::new(2, 4, tui.width - 4 - 2 - counter.get_width(), OverflowCut::Right),
             l_time_elapsed: Label::new(11, 2, 13, OverflowCut::Right),
@@ -320,6 +456,27 @@ impl StatusImageLoading {
         })
     }
 
+    /// Reports a loaded file, redrawing at most twice a second
+    pub fn file_loaded(&mut self, tui: &mut Tui, filename: &str, progress: u32) -> Result<()> {
+        tui.event(&Event::FileLoaded {
+            file: filename,
+            progress,
+            total: self.pbar.total,
+            elapsed: self.timer.elapsed().as_secs_f64(),
+        })?;
+        if self.timer.needs_update() {
+            self.update(tui, filename, progress)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self, tui: &mut Tui, files: u32) -> Result<()> {
+        tui.event(&Event::LoadingFinished {
+            files,
+            elapsed: self.timer.elapsed().as_secs_f64(),
+        })
+    }
+
     pub fn update(&mut self, tui: &mut Tui, filename: &str, progress: u32) -> Result<()> {
         self.l_filename.value = filename.into();
         self.c_counter.value = progress;
@@ -339,6 +496,7 @@ impl StatusImageLoading {
 }
 
 pub struct StatusCalculating {
+    caption: String,
     l_time_elapsed: Label,
     l_time_remaining: Label,
     c_attempts: RightCounter,
@@ -352,12 +510,13 @@ pub struct StatusCalculating {
 impl StatusCalculating {
     pub fn new(
         tui: &mut Tui,
+        caption: &str,
         total_attempts: u32,
         total_steps: u32,
         unique_colors: u64,
         fixed_colors: u64,
     ) -> Result<StatusCalculating> {
-        tui.prepare_block("Calculating palette", tui.offset, 12)?;
+        tui.prepare_block(caption, tui.offset, 12)?;
         let second_column = tui.width / 2;
         execute!(
             tui.out,
@@ -388,6 +547,7 @@ impl StatusCalculating {
             style::Print(fixed_colors.to_string()),
         )?;
         Ok(StatusCalculating {
+            caption: caption.to_string(),
             l_time_elapsed: Label::new(11, 2, 13, OverflowCut::Right),
             l_time_remaining: Label::new(second_column + 11, 2, 13, OverflowCut::Right),
             c_attempts: RightCounter::new(2 + 9, 4, total_attempts),
@@ -399,6 +559,29 @@ impl StatusCalculating {
         })
     }
 
+    /// Step stats are redrawn at most twice a second, but sent as JSON for every step
+    pub fn needs_update(&self, tui: &Tui) -> bool {
+        tui.is_json() || self.timer.needs_update()
+    }
+
+    pub fn attempt_started(&mut self, tui: &mut Tui, attempt: u32) -> Result<()> {
+        tui.event(&Event::AttemptStarted {
+            stage: &self.caption,
+            attempt,
+            attempts: self.c_attempts.maxval,
+        })
+    }
+
+    pub fn attempt_finished(&mut self, tui: &mut Tui, attempt: u32, steps: u32, error: f64) -> Result<()> {
+        tui.event(&Event::AttemptFinished {
+            stage: &self.caption,
+            attempt,
+            steps,
+            error,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn update(
         &mut self,
         tui: &mut Tui,
@@ -419,6 +602,16 @@ impl StatusCalculating {
         self.l_distance.value = format!("{:8.4}", distance);
         self.pbar.total = adjusted_total;
         self.pbar.progress = progress;
+        tui.event(&Event::Step {
+            stage: &self.caption,
+            attempt,
+            step,
+            points_changed: moved,
+            total_distance: distance,
+            progress,
+            total: adjusted_total,
+            elapsed: self.timer.elapsed().as_secs_f64(),
+        })?;
 
         self.l_time_elapsed.draw(tui)?;
         self.l_time_remaining.draw(tui)?;
@@ -431,3 +624,49 @@ impl StatusCalculating {
         Ok(())
     }
 }
+
+/// Table of results shown once a run has finished
+pub struct StatusResults;
+
+impl StatusResults {
+    /// The first column takes the remaining width, the others are right aligned
+    pub fn show(tui: &mut Tui, caption: &str, columns: &[&str], rows: &[Vec<String>]) -> Result<()> {
+        tui.prepare_block(caption, tui.offset, rows.len() as u16 + 3)?;
+        const COLUMN: usize = 10;
+        let first = (tui.width as usize - 4).saturating_sub(COLUMN * (columns.len() - 1));
+        let line = |cells: &[String]| -> String {
+            let mut text = String::new();
+            for (i, cell) in cells.iter().enumerate() {
+                if i == 0 {
+                    let mut cell = cell.clone();
+                    let chars = cell.chars().count();
+                    if chars >= first {
+                        cell = format!("...{}", cell.chars().skip(chars + 4 - first).collect::<String>());
+                    }
+                    text.push_str(&format!("{:<width$}", cell, width = first));
+                } else {
+                    text.push_str(&format!("{:>width$}", cell, width = COLUMN));
+                }
+            }
+            text
+        };
+        let header: Vec<String> = columns.iter().map(|column| column.to_string()).collect();
+        queue!(
+            tui.out,
+            style::SetBackgroundColor(Color::Grey),
+            style::SetForegroundColor(Color::Black),
+            cursor::MoveTo(2, 2 + tui.offset),
+            style::Print(line(&header)),
+            style::SetForegroundColor(Color::Red),
+        )?;
+        for (i, row) in rows.iter().enumerate() {
+            queue!(
+                tui.out,
+                cursor::MoveTo(2, 3 + i as u16 + tui.offset),
+                style::Print(line(row))
+            )?;
+        }
+        tui.refresh()?;
+        Ok(())
+    }
+}
@@ -1,76 +1,1191 @@
-use std::path::PathBuf;
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
-use colorcalc::{ColorCalc, ColorData};
-use interface::StatusImageLoading;
+use analyze::Analysis;
+use anyhow::{Context, Result};
+use autosize::{AutoColors, PaletteSize};
+use cache::HistogramCache;
+use clap::{Parser, Subcommand};
+use colorcalc::{Algorithm, ColorCalc, ColorData};
+use colors::FloatColor;
+use config::{Config, FullbrightConfig, WeightConfig};
+use cycles::CycleRange;
+use heatmap::HeatmapConfig;
+use histogram::{Group, Histogram, InputFile, Normalization};
+use html::HtmlReport;
+use image::RgbImage;
+use interface::{Event, ProgressFormat, StatusImageLoading, StatusResults, Timer, Tui};
+use palette::{OutputTarget, Palette, PaletteFormat, PlaypalTarget};
+use quality::{MeasureOptions, ReportSort, ReportTarget};
+use ramps::RampLayout;
+use remap::Remapper;
+use sort::{SortConfig, SortOrder};
+use tables::{BlendTable, BlendTarget, ColormapTarget, InverseMapTarget, ShadeCurve, TranslucencyTarget};
 
+mod analyze;
+mod archive;
+mod autosize;
+mod cache;
 mod colorcalc;
 mod colors;
+mod config;
+mod cycles;
+mod heatmap;
+mod histogram;
+mod html;
+mod inputs;
 mod interface;
+mod palette;
+mod quality;
+mod ramps;
+mod remap;
+mod sort;
+mod stabilize;
+mod tables;
 
-#[derive(Parser, Debug)]
-struct Args {
-    #[arg(required = true)]
+/// Input files and how they are loaded, shared by all commands
+#[derive(clap::Args, Debug)]
+struct InputArgs {
+    /// Image files, directories, glob patterns or WAD and PAK archives, ARCHIVE:LUMP selects lumps
     files: Vec<PathBuf>,
     #[arg(short = 't', long = "tex", num_args(0..))]
     tex_files: Vec<PathBuf>,
     #[arg(short='f', long = "fixed", num_args(0..))]
     fixed_files: Vec<PathBuf>,
+    /// Images whose colors fill the fullbright range
+    #[arg(short = 'b', long = "fullbright", num_args(0..))]
+    fullbright_files: Vec<PathBuf>,
+    /// Only load files matching this glob pattern
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip files matching this glob pattern
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Directory for cached per-file histograms
+    #[arg(long)]
+    cache: Option<PathBuf>,
+    /// Decode every file even if a cache directory is configured
+    #[arg(long)]
+    no_cache: bool,
+    /// Project config file [default: palcalc.toml if present]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// How progress is shown, json writes one event per line to stdout
+    #[arg(long, value_enum, default_value_t)]
+    progress_format: ProgressFormat,
+    /// Weight multiplier for matching files, as FILE=WEIGHT
+    #[arg(short = 'w', long = "weight", value_parser = histogram::parse_weight)]
+    weights: Vec<(String, f64)>,
+    /// Weight multiplier for the main files
+    #[arg(long)]
+    main_weight: Option<f64>,
+    /// Weight multiplier for the --tex files
+    #[arg(long)]
+    tex_weight: Option<f64>,
+    #[arg(short, long, value_enum)]
+    normalize: Option<Normalization>,
+    /// Skip pixels with alpha below this value
+    #[arg(long)]
+    alpha_threshold: Option<u8>,
+    /// Count partially transparent pixels proportionally to their alpha
+    #[arg(long)]
+    alpha_weighted: bool,
+    /// Transparent color key as RRGGBB, excluded from the histogram
+    #[arg(short = 'k', long = "color-key", value_parser = colors::parse_hex)]
+    color_keys: Vec<[u8; 3]>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ComputeArgs {
+    #[command(flatten)]
+    inputs: InputArgs,
+    /// Number of fullbright palette entries
+    #[arg(long)]
+    fullbright_count: Option<usize>,
+    /// First fullbright palette index [default: end of the palette]
+    #[arg(long)]
+    fullbright_start: Option<usize>,
+    /// Color cycling range filled with a gradient, as START+LENGTH=IMAGE or START+LENGTH=RRGGBB,RRGGBB,...
+    #[arg(long = "cycle", value_parser = cycles::parse_cycle)]
+    cycles: Vec<CycleRange>,
+    /// Animated GIF of the palette with the cycle ranges rotating, written to PATH
+    #[arg(long)]
+    cycle_preview: Option<PathBuf>,
+    /// Palette size, including fixed and reserved colors, or auto for the smallest one meeting --max-error or --max-delta-e
+    #[arg(short, long, value_parser = autosize::parse_size)]
+    colors: Option<PaletteSize>,
+    /// Largest mean pixel error in 8-bit RGB units an automatic palette size may have
+    #[arg(long)]
+    max_error: Option<f64>,
+    /// Largest mean CIEDE2000 difference an automatic palette size may have
+    #[arg(long)]
+    max_delta_e: Option<f64>,
+    #[arg(short, long, value_enum)]
+    algorithm: Option<Algorithm>,
+    /// Random seed for reproducible palettes
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    attempts: Option<u32>,
+    #[arg(long)]
+    max_steps: Option<u32>,
+    /// Lay the palette out as hue ramps, as ROWSxLENGTH
+    #[arg(long, value_parser = ramps::parse_layout)]
+    ramps: Option<RampLayout>,
+    /// Reorder the final palette, reserved and fixed colors keep their index
+    #[arg(long, value_enum)]
+    sort: Option<SortOrder>,
+    /// Table mapping old palette indices to sorted ones, written to PATH
+    #[arg(long)]
+    remap_table: Option<PathBuf>,
+    /// Keep the indices of this palette stable as far as possible
+    #[arg(long)]
+    previous: Option<PathBuf>,
+    /// Color always present in the palette, as RRGGBB
+    #[arg(short, long, value_parser = colors::parse_hex)]
+    reserved: Vec<[u8; 3]>,
+    /// Palette whose entries are all reserved
+    #[arg(long)]
+    reserved_palette: Option<PathBuf>,
+    /// Palette whose entries are kept like the colors of the --fixed images
+    #[arg(long)]
+    fixed_palette: Option<PathBuf>,
+    /// Palette the clustering starts from instead of random colors
+    #[arg(long)]
+    seed_palette: Option<PathBuf>,
+    /// Palette output file, format is guessed from the extension
+    #[arg(short = 'o', long = "output")]
+    outputs: Vec<PathBuf>,
+    #[command(flatten)]
+    colormaps: ColormapOptions,
+    #[command(flatten)]
+    blend: BlendOptions,
+    /// Self-contained HTML report of the run, written to PATH
+    #[arg(long)]
+    html: Option<PathBuf>,
+    /// Per-image quality report as CSV or JSON, written to PATH
+    #[arg(long)]
+    report: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    report_sort: Option<ReportSort>,
+    /// Pixel error above which a pixel counts as poorly represented, in 8-bit RGB units
+    #[arg(long)]
+    report_threshold: Option<f64>,
+    /// Error heatmap per input image, written to the DIR given as --heatmaps=DIR [default: report directory
+    /// next to the palette output]
+    #[arg(long, value_name = "DIR", num_args(0..=1), require_equals(true))]
+    heatmaps: Option<Option<PathBuf>>,
+}
+
+/// Light level tables built from a finished palette
+#[derive(clap::Args, Debug)]
+struct ColormapOptions {
+    /// Number of light levels in colormaps
     #[arg(short, long)]
-    shades: u32,
+    shades: Option<u32>,
+    /// Colormap fading to black, written to PATH
+    #[arg(long = "colormap")]
+    colormaps: Vec<PathBuf>,
+    /// Stack of Doom-style damage, bonus and radiation palettes, written to PATH
+    #[arg(long)]
+    playpal: Option<PathBuf>,
+    /// Inverse color map from RGB to palette index, written to PATH
+    #[arg(long)]
+    inverse_map: Option<PathBuf>,
+    /// Bits per channel of the inverse color map
+    #[arg(long, value_parser = clap::value_parser!(u32).range(5..=7))]
+    inverse_bits: Option<u32>,
+    /// Light color multiplied with every palette entry in all colormaps, as RRGGBB
+    #[arg(long, value_parser = colors::parse_hex)]
+    tint: Option<[u8; 3]>,
+    /// Color all colormaps fade towards instead of black, as RRGGBB
+    #[arg(long, value_parser = colors::parse_hex)]
+    fog: Option<[u8; 3]>,
+    /// How the light levels of all colormaps are spaced
+    #[arg(long, value_enum)]
+    shade_curve: Option<ShadeCurve>,
+    /// Exponent of the gamma shade curve
+    #[arg(long)]
+    shade_gamma: Option<f64>,
+}
+
+/// Blend tables built from a finished palette
+#[derive(clap::Args, Debug)]
+struct BlendOptions {
+    /// Translucency table at the given foreground opacity, as PERCENT=PATH
+    #[arg(long = "tranmap", value_parser = tables::parse_translucency)]
+    translucency: Vec<TranslucencyTarget>,
+    /// Additive or multiplicative blend table, as MODE=PATH
+    #[arg(long = "blend", value_parser = tables::parse_blend)]
+    blend: Vec<BlendTarget>,
+}
+
+/// Without a subcommand, palcalc computes a palette
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(flatten)]
+    compute: ComputeArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Calculate a palette from the input images and write it with its tables
+    Compute(Box<ComputeArgs>),
+    /// Convert the input images to indexed PNGs using an existing palette
+    Remap(RemapArgs),
+    /// Write colormaps, a playpal and an inverse map for an existing palette
+    Colormap(ColormapArgs),
+    /// Write translucency and blend tables for an existing palette
+    Blend(BlendArgs),
+    /// Report color statistics of the inputs and the error to expect per palette size
+    Analyze(AnalyzeArgs),
+    /// Draw an existing palette as a grid image, animated if it has cycle ranges
+    Preview(PreviewArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RemapArgs {
+    #[command(flatten)]
+    inputs: InputArgs,
+    /// Palette file the images are mapped to
+    #[arg(long)]
+    palette: PathBuf,
+    /// Directory the indexed images are written to
+    #[arg(long, default_value = "remapped")]
+    out_dir: PathBuf,
+    /// Palette index written for skipped pixels, which is marked transparent
+    #[arg(long)]
+    transparent_index: Option<u8>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ColormapArgs {
+    /// Palette file the tables are built for
+    #[arg(long)]
+    palette: PathBuf,
+    /// Project config file whose colormaps, playpal and inverse map are written [default: palcalc.toml if present]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: ColormapOptions,
+    /// How written files are reported, json writes one event per line to stdout
+    #[arg(long, value_enum, default_value_t)]
+    progress_format: ProgressFormat,
+}
+
+#[derive(clap::Args, Debug)]
+struct BlendArgs {
+    /// Palette file the tables are built for
+    #[arg(long)]
+    palette: PathBuf,
+    /// Project config file whose translucency and blend tables are written [default: palcalc.toml if present]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: BlendOptions,
+    /// How written files are reported, json writes one event per line to stdout
+    #[arg(long, value_enum, default_value_t)]
+    progress_format: ProgressFormat,
+}
+
+#[derive(clap::Args, Debug)]
+struct PreviewArgs {
+    /// Palette file to draw
+    #[arg(long)]
+    palette: PathBuf,
+    /// Color cycling range to animate in addition to the ones stored in the palette, as START+LENGTH
+    #[arg(long = "cycle", value_parser = cycles::parse_range)]
+    cycles: Vec<Range<usize>>,
+    /// Frame delay of the animation in milliseconds
+    #[arg(long, default_value_t = 100)]
+    delay: u32,
+    /// How written files are reported, json writes one event per line to stdout
+    #[arg(long, value_enum, default_value_t)]
+    progress_format: ProgressFormat,
+    /// Output image, a GIF when animated and a PNG otherwise
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct AnalyzeArgs {
+    #[command(flatten)]
+    inputs: InputArgs,
+    /// Largest palette size in the error estimate
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(2..=256))]
+    max_colors: u32,
+    /// Number of most frequent colors listed
+    #[arg(long, default_value_t = 16)]
+    top: usize,
+    /// Existing palette whose error on the inputs is reported
+    #[arg(long)]
+    palette: Option<PathBuf>,
+    /// Analysis written as JSON to PATH
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+fn to_patterns(files: Vec<PathBuf>) -> Vec<String> {
+    files.iter().map(|path| path.to_string_lossy().into_owned()).collect()
+}
+
+impl InputArgs {
+    /// Overrides the input settings of the config with the ones given on the command line
+    fn apply(self, config: &mut Config) {
+        let inputs = &mut config.inputs;
+        for (files, group) in [
+            (self.files, &mut inputs.adjustable),
+            (self.tex_files, &mut inputs.texture),
+            (self.fixed_files, &mut inputs.fixed),
+            (self.fullbright_files, &mut inputs.fullbright),
+        ] {
+            if !files.is_empty() {
+                group.files = to_patterns(files);
+            }
+        }
+        if let Some(weight) = self.main_weight {
+            inputs.adjustable.weight = weight;
+        }
+        if let Some(weight) = self.tex_weight {
+            inputs.texture.weight = weight;
+        }
+        config.weights.extend(
+            self.weights
+                .into_iter()
+                .map(|(files, weight)| WeightConfig { files, weight }),
+        );
+
+        config.include.extend(self.include);
+        config.exclude.extend(self.exclude);
+
+        if let Some(normalize) = self.normalize {
+            config.normalize = normalize;
+        }
+        if let Some(threshold) = self.alpha_threshold {
+            config.alpha.threshold = threshold;
+        }
+        if self.alpha_weighted {
+            config.alpha.weighted = true;
+        }
+        if !self.color_keys.is_empty() {
+            config.alpha.color_keys = self.color_keys;
+        }
+        if self.cache.is_some() {
+            config.cache = self.cache;
+        }
+        if self.no_cache {
+            config.cache = None;
+        }
+    }
+}
+
+impl ColormapOptions {
+    fn apply(self, config: &mut Config) {
+        if let Some(shades) = self.shades {
+            config.shades = shades;
+        }
+        if !self.colormaps.is_empty() {
+            config.colormaps = self.colormaps.into_iter().map(ColormapTarget::new).collect();
+        }
+        if let Some(path) = self.playpal {
+            config.playpal = Some(PlaypalTarget::new(path));
+        }
+        if let Some(path) = self.inverse_map {
+            config.inverse = Some(InverseMapTarget { path, bits: 6 });
+        }
+        if let (Some(inverse), Some(bits)) = (config.inverse.as_mut(), self.inverse_bits) {
+            inverse.bits = bits;
+        }
+        for colormap in config.colormaps.iter_mut() {
+            colormap.tint = self.tint.unwrap_or(colormap.tint);
+            colormap.fog = self.fog.unwrap_or(colormap.fog);
+            colormap.curve = self.shade_curve.unwrap_or(colormap.curve);
+            colormap.gamma = self.shade_gamma.unwrap_or(colormap.gamma);
+        }
+    }
+}
+
+impl BlendOptions {
+    fn apply(self, config: &mut Config) {
+        if !self.translucency.is_empty() {
+            config.translucency = self.translucency;
+        }
+        if !self.blend.is_empty() {
+            config.blend = self.blend;
+        }
+    }
+}
+
+impl ComputeArgs {
+    /// Overrides config values with the ones given on the command line
+    fn apply(self, config: &mut Config) {
+        self.inputs.apply(config);
+        match self.colors {
+            Some(PaletteSize::Count(colors)) => {
+                config.colors = colors;
+                config.auto_colors = None;
+            }
+            Some(PaletteSize::Auto) => {
+                config.auto_colors.get_or_insert_with(AutoColors::default);
+            }
+            None => {}
+        }
+        if self.max_error.is_some() || self.max_delta_e.is_some() {
+            let auto = config.auto_colors.get_or_insert_with(AutoColors::default);
+            auto.max_error = self.max_error.or(auto.max_error);
+            auto.max_delta_e = self.max_delta_e.or(auto.max_delta_e);
+        }
+        if let Some(algorithm) = self.algorithm {
+            config.algorithm = algorithm;
+        }
+        if self.seed.is_some() {
+            config.seed = self.seed;
+        }
+        if let Some(attempts) = self.attempts {
+            config.attempts = attempts;
+        }
+        if let Some(max_steps) = self.max_steps {
+            config.max_steps = max_steps;
+        }
+        if let Some(count) = self.fullbright_count {
+            let fullbright = config.fullbright.get_or_insert(FullbrightConfig {
+                start: None,
+                count,
+                colors: vec![],
+            });
+            fullbright.count = count;
+        }
+        if let (Some(fullbright), Some(start)) = (config.fullbright.as_mut(), self.fullbright_start) {
+            fullbright.start = Some(start);
+        }
+        if !self.cycles.is_empty() {
+            config.cycling.ranges = self.cycles;
+        }
+        if self.cycle_preview.is_some() {
+            config.cycling.preview = self.cycle_preview;
+        }
+        if self.ramps.is_some() {
+            config.ramps = self.ramps;
+        }
+        if let Some(order) = self.sort {
+            let remap = config.sort.take().and_then(|sort| sort.remap);
+            config.sort = Some(SortConfig { order, remap });
+        }
+        if let (Some(sort), Some(path)) = (config.sort.as_mut(), self.remap_table) {
+            sort.remap = Some(path);
+        }
+        if self.previous.is_some() {
+            config.previous = self.previous;
+        }
+        if !self.reserved.is_empty() {
+            config.reserved = self.reserved;
+        }
+        if self.reserved_palette.is_some() {
+            config.reserved_palette = self.reserved_palette;
+        }
+        if self.fixed_palette.is_some() {
+            config.fixed_palette = self.fixed_palette;
+        }
+        if self.seed_palette.is_some() {
+            config.seed_palette = self.seed_palette;
+        }
+        self.colormaps.apply(config);
+        self.blend.apply(config);
+        if self.html.is_some() {
+            config.html = self.html;
+        }
+        if let Some(path) = self.report {
+            config.report = Some(ReportTarget::new(path));
+        }
+        if let Some(report) = config.report.as_mut() {
+            if let Some(sort) = self.report_sort {
+                report.sort = sort;
+            }
+            if let Some(threshold) = self.report_threshold {
+                report.threshold = threshold;
+            }
+        }
+        if !self.outputs.is_empty() {
+            config.outputs = self
+                .outputs
+                .into_iter()
+                .map(|path| OutputTarget { path, format: None })
+                .collect();
+        }
+        // The default directory depends on the palette outputs, so it comes after them
+        if let Some(dir) = self.heatmaps {
+            let dir = dir.unwrap_or_else(|| {
+                let output = config.outputs.first().map(|output| output.path.clone());
+                let parent = output
+                    .as_deref()
+                    .and_then(|path| path.parent())
+                    .unwrap_or(Path::new(""));
+                parent.join("report")
+            });
+            config.heatmaps = Some(HeatmapConfig::new(dir));
+        }
+    }
+}
+
+/// Input files of the config, exits if there are none
+fn input_files(config: &Config) -> Vec<InputFile> {
+    let input_files = config.input_files().unwrap();
+    if input_files.is_empty() {
+        eprintln!("No input files given");
+        std::process::exit(2);
+    }
+    input_files
+}
+
+/// Closes the interface and exits with `error`, for failures caused by the inputs rather than by palcalc
+fn exit_with_error(tui: Tui, error: anyhow::Error) -> ! {
+    drop(tui);
+    eprintln!("Error: {:#}", error);
+    std::process::exit(1);
+}
+
+/// Adds a written output to the summary and reports it as a progress event
+fn output_written(tui: &mut Tui, summary: &mut Vec<String>, path: &Path, message: String) {
+    tui.event(&Event::OutputWritten {
+        path: &path.to_string_lossy(),
+        message: &message,
+    })
+    .unwrap();
+    summary.push(message);
+}
+
+/// Loads every input file, from the histogram cache if one is configured
+fn load_histograms(
+    config: &Config,
+    input_files: Vec<InputFile>,
+    mut cache: Option<&mut HistogramCache>,
+    tui: &mut Tui,
+) -> Result<Vec<(InputFile, Histogram)>> {
+    let group_count = |group| input_files.iter().filter(|file| file.group == group).count() as u32;
+    let mut status_loading = StatusImageLoading::new(
+        tui,
+        &[
+            ("Adjustable", group_count(Group::Main)),
+            ("Texture", group_count(Group::Texture)),
+            ("Fixed", group_count(Group::Fixed)),
+            ("Fullbright", group_count(Group::Fullbright)),
+        ],
+    )?;
+
+    status_loading.timer.start();
+    status_loading.update(tui, "", 0)?;
+    let mut progress = 0;
+
+    let mut histograms = vec![];
+    for file in input_files {
+        let histogram = match cache.as_mut() {
+            Some(cache) => cache.load(&file.path, &config.alpha),
+            None => Histogram::load(&file.path, &config.alpha),
+        }
+        .with_context(|| format!("can't load {}", file.path.display()))?;
+        progress += 1;
+        status_loading.file_loaded(tui, &file.path.to_string_lossy(), progress)?;
+        histograms.push((file, histogram));
+        //thread::sleep(Duration::from_millis(300));
+    }
+    status_loading.finish(tui, progress)?;
+    Ok(histograms)
+}
+
+/// Prints the lines shown after the interface closes, to stderr in JSON mode to keep stdout a clean event stream
+fn print_summary(format: ProgressFormat, cache: Option<HistogramCache>, total_files: usize, summary: Vec<String>) {
+    let print = |line: String| {
+        if format == ProgressFormat::Json {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    };
+    if let Some(cache) = cache {
+        print(format!(
+            "{} of {} histograms loaded from cache",
+            cache.hits, total_files
+        ));
+    }
+    for line in summary {
+        print(line);
+    }
+}
+
+/// Files written from a finished palette with their summary lines, and the rendered tables
+#[derive(Default)]
+struct TableOutputs {
+    written: Vec<(PathBuf, String)>,
+    previews: Vec<(String, RgbImage)>,
+}
+
+/// Writes the translucency, blend and colormap tables, the playpal and the inverse map of `config`
+fn write_tables(config: &Config, palette: &Palette, previews: bool) -> Result<TableOutputs> {
+    let table_summary = |name: String, path: &PathBuf, table: BlendTable| {
+        format!(
+            "{} table written to {} (mean error {:.2}, max {:.2})",
+            name,
+            path.display(),
+            table.mean_error,
+            table.max_error
+        )
+    };
+    let mut outputs = TableOutputs::default();
+    for target in config.translucency.iter() {
+        let table = target.write(palette)?;
+        if previews {
+            outputs
+                .previews
+                .push((format!("{}% translucency", target.opacity), table.preview(palette)));
+        }
+        outputs.written.push((
+            target.path.clone(),
+            table_summary(format!("{}% translucency", target.opacity), &target.path, table),
+        ));
+    }
+    for target in config.blend.iter() {
+        let table = target.write(palette)?;
+        if previews {
+            outputs
+                .previews
+                .push((format!("{:?} blend", target.mode), table.preview(palette)));
+        }
+        outputs.written.push((
+            target.path.clone(),
+            table_summary(format!("{:?}", target.mode), &target.path, table),
+        ));
+    }
+    for target in config.colormaps.iter() {
+        let colormap = target.write(palette, config.shades)?;
+        if previews {
+            outputs
+                .previews
+                .push((format!("Colormap {}", target.name()), colormap.preview(palette)));
+        }
+        outputs.written.push((
+            target.path.clone(),
+            format!("Colormap {} written to {}", target.name(), target.path.display()),
+        ));
+    }
+    if let Some(target) = &config.playpal {
+        let count = target.write(palette)?;
+        outputs.written.push((
+            target.path.clone(),
+            format!(
+                "{} palettes ({}) written to {}",
+                count,
+                target.describe(),
+                target.path.display()
+            ),
+        ));
+    }
+    if let Some(target) = &config.inverse {
+        let size = target.write(palette)?;
+        outputs.written.push((
+            target.path.clone(),
+            format!(
+                "{}-bit inverse color map ({} bytes) written to {}",
+                target.bits,
+                size,
+                target.path.display()
+            ),
+        ));
+    }
+
+    Ok(outputs)
 }
 
 fn main() {
     let args = Args::parse_from(wild::args());
+    match args.command {
+        Some(Command::Compute(args)) => compute(*args),
+        Some(Command::Remap(args)) => remap(args),
+        Some(Command::Colormap(args)) => colormap(args),
+        Some(Command::Blend(args)) => blend(args),
+        Some(Command::Analyze(args)) => analyze(args),
+        Some(Command::Preview(args)) => preview(args),
+        None => compute(args.compute),
+    }
+}
+
+fn remap(args: RemapArgs) {
+    let progress_format = args.inputs.progress_format;
+    let mut config = Config::load(args.inputs.config.as_deref()).unwrap();
+    args.inputs.apply(&mut config);
+    let input_files = input_files(&config);
+    let palette = Palette::read(&args.palette).unwrap();
+
+    let remapper = Remapper::new(&palette, &config.alpha, args.transparent_index).unwrap();
+    let written = remapper.write_all(&input_files, &args.out_dir).unwrap();
+    if progress_format == ProgressFormat::Json {
+        for path in written.iter() {
+            report_written(
+                progress_format,
+                path,
+                format!("Remapped image written to {}", path.display()),
+            );
+        }
+    }
+    report_written(
+        progress_format,
+        &args.out_dir,
+        format!(
+            "{} images remapped to {} colors written to {}",
+            written.len(),
+            palette.colors.len(),
+            args.out_dir.display()
+        ),
+    );
+}
+
+/// Reports a file written by the commands without a progress screen, as an event or as a plain line
+fn report_written(format: ProgressFormat, path: &Path, message: String) {
+    match format {
+        ProgressFormat::Json => interface::write_event(&Event::OutputWritten {
+            path: &path.to_string_lossy(),
+            message: &message,
+        })
+        .unwrap(),
+        ProgressFormat::Tui => println!("{}", message),
+    }
+}
+
+/// Writes the tables of `config` for the palette read from `path`
+fn write_palette_tables(config: &Config, path: &Path, format: ProgressFormat) {
+    config.validate().unwrap();
+    let palette = Palette::read(path).unwrap();
+    let outputs = write_tables(config, &palette, false).unwrap();
+    if outputs.written.is_empty() {
+        eprintln!("No tables given");
+        std::process::exit(2);
+    }
+    for (path, message) in outputs.written {
+        report_written(format, &path, message);
+    }
+}
+
+fn colormap(args: ColormapArgs) {
+    let mut config = Config::load(args.config.as_deref()).unwrap();
+    config.translucency.clear();
+    config.blend.clear();
+    args.options.apply(&mut config);
+    write_palette_tables(&config, &args.palette, args.progress_format);
+}
+
+fn blend(args: BlendArgs) {
+    let mut config = Config::load(args.config.as_deref()).unwrap();
+    config.colormaps.clear();
+    config.playpal = None;
+    config.inverse = None;
+    args.options.apply(&mut config);
+    write_palette_tables(&config, &args.palette, args.progress_format);
+}
+
+fn preview(args: PreviewArgs) {
+    let palette = Palette::read(&args.palette).unwrap();
+    let ranges: Vec<(Range<usize>, bool)> = palette
+        .cycles
+        .iter()
+        .map(|(_, range)| range.clone())
+        .chain(args.cycles)
+        .map(|range| (range, false))
+        .collect();
+    if ranges.is_empty() {
+        palette
+            .write(&OutputTarget {
+                path: args.output.clone(),
+                format: Some(PaletteFormat::Png),
+            })
+            .unwrap();
+        report_written(
+            args.progress_format,
+            &args.output,
+            format!("Palette preview written to {}", args.output.display()),
+        );
+    } else {
+        let frames = cycles::write_preview(&palette, &ranges, args.delay, &args.output).unwrap();
+        report_written(
+            args.progress_format,
+            &args.output,
+            format!(
+                "Cycling preview of {} ranges ({} frames) written to {}",
+                ranges.len(),
+                frames,
+                args.output.display()
+            ),
+        );
+    }
+}
+
+fn analyze(args: AnalyzeArgs) {
+    let progress_format = args.inputs.progress_format;
+    let mut config = Config::load(args.inputs.config.as_deref()).unwrap();
+    args.inputs.apply(&mut config);
+    let input_files = input_files(&config);
 
-    let mut tui = interface::Tui::new().unwrap();
+    let mut tui = Tui::new(progress_format).unwrap();
     tui.show_logo().unwrap();
+    let total_files = input_files.len();
+    let mut cache = config.cache.as_deref().map(|dir| HistogramCache::new(dir).unwrap());
+    let palette = args.palette.as_deref().map(|path| Palette::read(path).unwrap());
+    let histograms = match load_histograms(&config, input_files, cache.as_mut(), &mut tui) {
+        Ok(histograms) => histograms,
+        Err(e) => exit_with_error(tui, e),
+    };
+    let analysis = Analysis::new(
+        &histograms,
+        &config,
+        args.top,
+        args.max_colors,
+        palette.as_ref(),
+        &mut tui,
+    )
+    .unwrap();
+    StatusResults::show(
+        &mut tui,
+        "Error by palette size",
+        &["Colors", "RMS error", "Gain"],
+        &analysis.curve_rows(),
+    )
+    .unwrap();
+
+    let mut summary = analysis.summary();
+    if let Some(path) = &args.json {
+        analysis.write_json(path).unwrap();
+        output_written(
+            &mut tui,
+            &mut summary,
+            path,
+            format!("Analysis written to {}", path.display()),
+        );
+    }
+    tui.wait_for_key().unwrap();
+    drop(tui);
+    print_summary(progress_format, cache, total_files, summary);
+}
 
-    let fixed_images = args.fixed_files;
-    let adjustable_images = {
-        let mut vector = args.files;
-        let mut add_vector = args.tex_files;
-        vector.append(&mut add_vector);
-        vector
+fn compute(args: ComputeArgs) {
+    let progress_format = args.inputs.progress_format;
+    let mut config = Config::load(args.inputs.config.as_deref()).unwrap();
+    args.apply(&mut config);
+
+    let input_files = input_files(&config);
+    config.validate().unwrap();
+    // Pick the seed here rather than in ColorCalc so the report can show it and the run can be repeated
+    config.seed.get_or_insert_with(rand::random);
+    let previous = config.previous.as_deref().map(|path| Palette::read(path).unwrap());
+    let read_colors = |path: &Option<PathBuf>| path.as_deref().map(|path| Palette::read(path).unwrap().colors);
+    if let Some(colors) = read_colors(&config.reserved_palette) {
+        config.reserved.extend(colors);
+    }
+    let fixed_palette = read_colors(&config.fixed_palette);
+    let seed_palette = read_colors(&config.seed_palette);
+
+    let mut html = config.html.as_ref().map(|_| HtmlReport::default());
+    let mut phase = Timer::new(0);
+    let mut phases: Vec<(String, std::time::Duration)> = vec![];
+    let mut end_phase = |name: &str, phase: &mut Timer| {
+        phases.push((name.to_string(), phase.elapsed()));
+        phase.start();
     };
 
-    let total_files = (fixed_images.len() + adjustable_images.len()) as u32;
+    let mut tui = Tui::new(progress_format).unwrap();
+    tui.show_logo().unwrap();
+
+    let total_files = input_files.len();
+    let mut cache = config.cache.as_deref().map(|dir| HistogramCache::new(dir).unwrap());
+    let histograms = match load_histograms(&config, input_files, cache.as_mut(), &mut tui) {
+        Ok(histograms) => histograms,
+        Err(e) => exit_with_error(tui, e),
+    };
+    end_phase("Loading images", &mut phase);
 
+    let group_weights = config.group_weights();
     let mut adjustable_colors = ColorData::new();
     let mut fixed_colors = ColorData::new();
+    for group in [Group::Main, Group::Texture] {
+        histogram::merge(
+            &histograms,
+            config.normalize,
+            &group_weights,
+            &mut adjustable_colors,
+            group,
+        );
+    }
+    histogram::merge(
+        &histograms,
+        config.normalize,
+        &group_weights,
+        &mut fixed_colors,
+        Group::Fixed,
+    );
+    if let Some(colors) = &fixed_palette {
+        fixed_colors.add_colors(colors);
+    }
 
-    let mut status_loading = StatusImageLoading::new(&mut tui, total_files).unwrap();
+    let mut summary = vec![];
+    if let Some(auto) = config.auto_colors.clone() {
+        let points: Vec<_> = adjustable_colors
+            .colors()
+            .into_iter()
+            .map(|(color, count)| (FloatColor::from_rgb8(color), count))
+            .collect();
+        let fixed = colorcalc::fixed_list(&fixed_colors, &config.reserved);
+        let (colors, trials) = auto.search(&config, &points, &fixed, &mut tui).unwrap();
+        config.colors = colors;
+        // Ranges placed relative to the end of the palette only have their final position now
+        if let Err(e) = config.validate() {
+            exit_with_error(tui, e);
+        }
+        match trials.iter().find(|trial| trial.colors == colors && trial.meets) {
+            Some(trial) => summary.push(format!(
+                "{} colors chosen for {} (mean error {:.2}, mean dE2000 {:.2}, {} sizes tried)",
+                colors,
+                auto.describe(),
+                trial.mean_error,
+                trial.mean_delta_e,
+                trials.len()
+            )),
+            None => summary.push(format!("No palette size up to {} meets {}", colors, auto.describe())),
+        }
+        end_phase("Choosing palette size", &mut phase);
+    }
 
-    status_loading.timer.start();
-    status_loading.update(&mut tui, "", 0).unwrap();
-    let mut progress = 0;
+    // Colors of the previous palette that seed the lit and the fullbright clustering
+    let fullbright_range = config.fullbright_range().unwrap_or(0..0);
+    let previous_seeds = |fullbright: bool| -> Vec<[u8; 3]> {
+        let Some(previous) = &previous else {
+            return vec![];
+        };
+        (0..previous.colors.len())
+            .filter(|i| fullbright_range.contains(i) == fullbright)
+            .map(|i| previous.colors[i])
+            .collect()
+    };
 
-    for filename in adjustable_images.iter() {
-        adjustable_colors.add(filename).unwrap();
-        progress += 1;
-        if status_loading.timer.needs_update() {
-            status_loading
-                .update(&mut tui, filename.to_str().unwrap(), progress)
-                .unwrap();
+    let mut palette = match &config.ramps {
+        Some(layout) => ramps::calculate(
+            layout,
+            &config.calc_options(),
+            adjustable_colors,
+            fixed_colors,
+            &config.reserved,
+            &mut tui,
+        )
+        .unwrap(),
+        None => {
+            let mut calculator = ColorCalc::new(
+                &config.calc_options(),
+                adjustable_colors,
+                fixed_colors,
+                &config.reserved,
+            )
+            .unwrap();
+            calculator.seed_centroids(seed_palette.as_deref().unwrap_or(&previous_seeds(false)));
+            let mut status_calc = interface::StatusCalculating::new(
+                &mut tui,
+                "Calculating palette",
+                config.attempts,
+                config.max_steps,
+                calculator.unique_colors,
+                calculator.fixed_colors,
+            )
+            .unwrap();
+            calculator.run(&mut status_calc, &mut tui).unwrap();
+            calculator.palette()
         }
-        //thread::sleep(Duration::from_millis(300));
+    };
+
+    end_phase("Calculating palette", &mut phase);
+
+    let mut blocks = vec![];
+    if let (Some(options), Some(range), Some(fullbright)) = (
+        config.fullbright_calc_options(),
+        config.fullbright_range(),
+        config.fullbright.as_ref(),
+    ) {
+        let mut fullbright_colors = ColorData::new();
+        histogram::merge(
+            &histograms,
+            config.normalize,
+            &group_weights,
+            &mut fullbright_colors,
+            Group::Fullbright,
+        );
+        let mut calculator = ColorCalc::new(&options, fullbright_colors, ColorData::new(), &fullbright.colors).unwrap();
+        calculator.seed_centroids(&previous_seeds(true));
+        let mut status_calc = interface::StatusCalculating::new(
+            &mut tui,
+            "Calculating fullbright colors",
+            config.attempts,
+            config.max_steps,
+            calculator.unique_colors,
+            calculator.fixed_colors,
+        )
+        .unwrap();
+        calculator.run(&mut status_calc, &mut tui).unwrap();
+        let mut block = calculator.palette();
+        block.resize(range.len());
+        block.fullbright = 0..range.len();
+        blocks.push((range.start, block));
     }
-    for filename in fixed_images.iter() {
-        fixed_colors.add(filename).unwrap();
-        progress += 1;
-        if status_loading.timer.needs_update() {
-            status_loading
-                .update(&mut tui, filename.to_str().unwrap(), progress)
-                .unwrap();
+    for cycle in config.cycling.ranges.iter() {
+        blocks.push((cycle.start, cycle.palette().unwrap()));
+    }
+    // Inserting in index order keeps every block at its configured start
+    blocks.sort_by_key(|(start, _)| *start);
+    for (start, block) in blocks {
+        palette = palette.insert(block, start).unwrap();
+    }
+    let input_files: Vec<_> = histograms.into_iter().map(|(file, _)| file).collect();
+    end_phase("Fullbright and cycle ranges", &mut phase);
+
+    if let Some(previous) = &previous {
+        palette = stabilize::stabilize(&palette, previous);
+        let changes = stabilize::changes(&palette, previous);
+        let total = palette.colors.len().max(previous.colors.len());
+        if changes.is_empty() {
+            summary.push(format!("All {} indices match the previous palette", total));
+        } else {
+            let max = changes.iter().map(|change| change.distance).fold(0.0, f64::max);
+            let mean = changes.iter().map(|change| change.distance).sum::<f64>() / changes.len() as f64;
+            summary.push(format!(
+                "{} of {} indices changed (mean shift {:.2}, max {:.2})",
+                changes.len(),
+                total,
+                mean,
+                max
+            ));
+        }
+        for change in changes {
+            let [r0, g0, b0] = change.old;
+            let [r1, g1, b1] = change.new;
+            summary.push(format!(
+                "  {:>3}: {:02x}{:02x}{:02x} -> {:02x}{:02x}{:02x} ({:.2})",
+                change.index, r0, g0, b0, r1, g1, b1, change.distance
+            ));
+        }
+    }
+    if let Some(sort) = &config.sort {
+        palette = sort.apply(&palette).unwrap();
+        if let Some(path) = &sort.remap {
+            output_written(
+                &mut tui,
+                &mut summary,
+                path,
+                format!("Remap table written to {}", path.display()),
+            );
         }
-        //thread::sleep(Duration::from_millis(300));
     }
+    for output in config.outputs.iter() {
+        let sidecar = palette.write(output).unwrap();
+        output_written(
+            &mut tui,
+            &mut summary,
+            &output.path,
+            format!("Palette written to {}", output.path.display()),
+        );
+        if let Some(path) = sidecar {
+            output_written(
+                &mut tui,
+                &mut summary,
+                &path,
+                format!("Cycle ranges written to {}", path.display()),
+            );
+        }
+    }
+    let outputs = write_tables(&config, &palette, html.is_some()).unwrap();
+    for (path, message) in outputs.written {
+        output_written(&mut tui, &mut summary, &path, message);
+    }
+    let tables = outputs.previews;
+    if let Some(path) = &config.cycling.preview {
+        let frames = config.cycling.write_preview(&palette, path).unwrap();
+        output_written(
+            &mut tui,
+            &mut summary,
+            path,
+            format!("Cycling preview ({} frames) written to {}", frames, path.display()),
+        );
+    }
+
+    end_phase("Writing outputs", &mut phase);
 
-    let mut calculator = ColorCalc::new(255, adjustable_colors, fixed_colors);
-    let mut status_calc =
-        interface::StatusCalculating::new(&mut tui, 5, 1000, calculator.unique_colors, calculator.fixed_colors)
+    if config.report.is_some() || config.heatmaps.is_some() || html.is_some() {
+        let options = MeasureOptions {
+            threshold: config
+                .report
+                .as_ref()
+                .map_or(quality::default_threshold(), |r| r.threshold),
+            heatmaps: config.heatmaps.as_ref(),
+            thumbnails: html.as_ref().map(|_| html::THUMBNAIL_SIZE),
+        };
+        let mut report = match quality::measure_all(&input_files, &palette, &config.alpha, &options) {
+            Ok(report) => report,
+            Err(e) => exit_with_error(tui, e),
+        };
+        if let Some(heatmaps) = &config.heatmaps {
+            output_written(
+                &mut tui,
+                &mut summary,
+                &heatmaps.dir,
+                format!("{} error heatmaps written to {}", report.len(), heatmaps.dir.display()),
+            );
+        }
+        if let Some(target) = &config.report {
+            target.sort(&mut report);
+            target.write(&report).unwrap();
+            StatusResults::show(
+                &mut tui,
+                "Worst images",
+                &["File", "MSE", "PSNR", "Max", "Above", "dE2000", "SSIM"],
+                &quality::summary_rows(&report, target.top),
+            )
             .unwrap();
-    calculator.run(&mut status_calc, &mut tui).unwrap();
+            output_written(
+                &mut tui,
+                &mut summary,
+                &target.path,
+                format!(
+                    "Quality report for {} images written to {}",
+                    report.len(),
+                    target.path.display()
+                ),
+            );
+            for row in quality::summary_rows(&report, target.top) {
+                summary.push(format!(
+                    "  {} (MSE {}, PSNR {} dB, max {}, {} above {}, dE2000 {}, SSIM {})",
+                    row[0], row[1], row[2], row[3], row[4], target.threshold, row[5], row[6]
+                ));
+            }
+        }
+        end_phase("Measuring quality", &mut phase);
+        if let Some(html) = html.as_mut() {
+            html.images = report;
+        }
+    }
+    if let (Some(mut html), Some(path)) = (html, &config.html) {
+        html.parameters = config.parameters();
+        html.phases = phases;
+        html.tables = tables;
+        html.write(&palette, path).unwrap();
+        output_written(
+            &mut tui,
+            &mut summary,
+            path,
+            format!("HTML report written to {}", path.display()),
+        );
+    }
 
     //thread::sleep(Duration::from_secs(3));
+    if config.report.is_some() {
+        // The worst images are drawn last and would vanish with the screen
+        tui.wait_for_key().unwrap();
+    }
+    drop(tui);
+    print_summary(progress_format, cache, total_files, summary);
 }
@@ -0,0 +1,770 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Seek, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use image::{ImageFormat, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    colors::{self, FloatColor},
+    config::deserialize_color,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaletteFormat {
+    /// JASC-PAL text file
+    Jasc,
+    /// Raw 768 bytes of RGB triplets
+    Raw,
+    /// GIMP palette
+    Gpl,
+    /// One RRGGBB value per line
+    Hex,
+    /// 16x16 grid preview image
+    Png,
+}
+
+impl PaletteFormat {
+    pub fn from_path(path: &Path) -> Option<PaletteFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "pal" => Some(PaletteFormat::Jasc),
+            "act" | "lmp" | "raw" => Some(PaletteFormat::Raw),
+            "gpl" => Some(PaletteFormat::Gpl),
+            "hex" | "txt" => Some(PaletteFormat::Hex),
+            "png" => Some(PaletteFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputTarget {
+    pub path: PathBuf,
+    pub format: Option<PaletteFormat>,
+}
+
+impl OutputTarget {
+    pub fn format(&self) -> Result<PaletteFormat> {
+        self.format
+            .or_else(|| PaletteFormat::from_path(&self.path))
+            .ok_or_else(|| anyhow!("can't guess palette format of {}", self.path.display()))
+    }
+}
+
+/// Largest palette the indexed formats and tables can address
+pub const MAX_COLORS: usize = 256;
+
+#[derive(Clone)]
+pub struct Palette {
+    pub colors: Vec<[u8; 3]>,
+    /// Weighted pixel count assigned to every entry
+    pub population: Vec<f64>,
+    /// Reserved and fixed entries, which must keep their index
+    pub locked: Vec<bool>,
+    /// Indices that keep full brightness in every colormap level
+    pub fullbright: Range<usize>,
+    /// Named index ranges whose colors rotate at runtime
+    pub cycles: Vec<(String, Range<usize>)>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<[u8; 3]>) -> Palette {
+        Palette {
+            population: vec![0.0; colors.len()],
+            locked: vec![false; colors.len()],
+            colors,
+            fullbright: 0..0,
+            cycles: vec![],
+        }
+    }
+
+    /// Marks the first `count` entries as locked
+    pub fn lock_first(mut self, count: usize) -> Palette {
+        for locked in self.locked.iter_mut().take(count) {
+            *locked = true;
+        }
+        self
+    }
+
+    /// Inserts `block` at `start`, padding with black up to `start` and shifting the following colors.
+    /// The fullbright and cycle ranges of `block` are carried over.
+    pub fn insert(mut self, block: Palette, start: usize) -> Result<Palette> {
+        let len = block.colors.len();
+        if self.colors.len().max(start) + len > MAX_COLORS {
+            return Err(anyhow!(
+                "inserting {} entries at {} grows the palette past {} colors",
+                len,
+                start,
+                MAX_COLORS
+            ));
+        }
+        let shift = |range: &Range<usize>| match range.start >= start {
+            true => range.start + len..range.end + len,
+            false => range.clone(),
+        };
+        self.fullbright = shift(&self.fullbright);
+        for (_, range) in self.cycles.iter_mut() {
+            *range = shift(range);
+        }
+        if !block.fullbright.is_empty() {
+            self.fullbright = block.fullbright.start + start..block.fullbright.end + start;
+        }
+        for (name, range) in block.cycles {
+            self.cycles.push((name, range.start + start..range.end + start));
+        }
+        self.cycles.sort_by_key(|(_, range)| range.start);
+
+        self.resize(self.colors.len().max(start));
+        self.colors.splice(start..start, block.colors);
+        self.population.splice(start..start, block.population);
+        self.locked.splice(start..start, block.locked);
+        Ok(self)
+    }
+
+    /// Truncates or pads the palette with unused black entries
+    pub fn resize(&mut self, len: usize) {
+        self.colors.resize(len, [0, 0, 0]);
+        self.population.resize(len, 0.0);
+        self.locked.resize(len, false);
+    }
+
+    pub fn is_fullbright(&self, index: usize) -> bool {
+        self.fullbright.contains(&index)
+    }
+
+    pub fn is_cycling(&self, index: usize) -> bool {
+        self.cycles.iter().any(|(_, range)| range.contains(&index))
+    }
+
+    /// Indices and colors of the entries that neither stay fullbright nor rotate at runtime,
+    /// the only ones shaded or blended colors may land on
+    pub fn lit_entries(&self) -> (Vec<usize>, Vec<FloatColor>) {
+        self.float_colors()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !self.is_fullbright(*i) && !self.is_cycling(*i))
+            .unzip()
+    }
+
+    pub fn float_colors(&self) -> Vec<FloatColor> {
+        self.colors.iter().map(|&color| FloatColor::from_rgb8(color)).collect()
+    }
+
+    /// Palette with every color moved towards `tint` by `amount` (0 to 1)
+    pub fn tinted(&self, tint: [u8; 3], amount: f64) -> Palette {
+        let tint = FloatColor::from_rgb8(tint);
+        Palette {
+            colors: self
+                .float_colors()
+                .into_iter()
+                .map(|color| color.mix(tint, amount).to_rgb8())
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Color of a palette index, unused indices are black
+    pub fn color(&self, index: usize) -> [u8; 3] {
+        *self.colors.get(index).unwrap_or(&[0, 0, 0])
+    }
+
+    /// Reads a palette in any supported format, see `decode`
+    pub fn read(path: &Path) -> Result<Palette> {
+        let data = fs::read(path).with_context(|| format!("can't read {}", path.display()))?;
+        let mut palette =
+            Palette::decode(path, &data).with_context(|| format!("can't read palette {}", path.display()))?;
+        let sidecar = cycles_path(path);
+        if palette.cycles.is_empty() && sidecar.is_file() {
+            palette.cycles = read_cycles(&sidecar).with_context(|| format!("can't read {}", sidecar.display()))?;
+        }
+        Ok(palette)
+    }
+
+    /// Decodes a palette file named `path`. Besides the output formats this reads Microsoft RIFF
+    /// palettes, Adobe color tables, Doom PLAYPAL lumps and the palettes embedded in indexed PNG,
+    /// GIF, BMP and PCX images. Raw data with more than 256 colors, like a PLAYPAL, yields its first palette.
+    pub fn decode(path: &Path, data: &[u8]) -> Result<Palette> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let is_playpal = path
+            .file_stem()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case("playpal"));
+        // Editors on Windows like to start text files with a byte order mark
+        let text_data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        let text = || std::str::from_utf8(text_data).map_err(|_| anyhow!("not a text file"));
+        let mut cycles = vec![];
+        let colors = match extension.as_str() {
+            "pal" if text_data.starts_with(b"JASC-PAL") => Palette::read_jasc(text()?)?,
+            "pal" if data.starts_with(b"RIFF") => read_riff(data)?,
+            "pal" | "lmp" | "raw" => read_raw(data)?,
+            "" if is_playpal => read_raw(data)?,
+            "act" => read_act(data)?,
+            "gpl" => {
+                cycles = Palette::read_gpl_cycles(text()?);
+                Palette::read_gpl(text()?)?
+            }
+            "hex" | "txt" => text()?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(colors::parse_hex)
+                .collect::<Result<_>>()?,
+            "png" => match read_png_plte(data)? {
+                Some(colors) => colors,
+                None => Palette::read_png(path, data)?,
+            },
+            "gif" => read_gif(data)?,
+            "bmp" => read_bmp(data)?,
+            "pcx" => read_pcx(data)?,
+            _ => return Err(anyhow!("can't guess palette format of {}", path.display())),
+        };
+        if colors.is_empty() {
+            return Err(anyhow!("{} has no colors", path.display()));
+        }
+        if colors.len() > MAX_COLORS {
+            return Err(anyhow!(
+                "{} has {} colors, more than {}",
+                path.display(),
+                colors.len(),
+                MAX_COLORS
+            ));
+        }
+        Ok(Palette {
+            cycles,
+            ..Palette::new(colors)
+        })
+    }
+
+    fn read_jasc(text: &str) -> Result<Vec<[u8; 3]>> {
+        let mut lines = text.lines().map(str::trim);
+        if lines.next() != Some("JASC-PAL") {
+            return Err(anyhow!("missing JASC-PAL header"));
+        }
+        lines.next();
+        let count: usize = lines.next().unwrap_or_default().parse()?;
+        let colors = lines
+            .filter(|line| !line.is_empty())
+            .take(count)
+            .map(parse_triplet)
+            .collect::<Result<Vec<_>>>()?;
+        if colors.len() != count {
+            return Err(anyhow!("expected {} colors, got {}", count, colors.len()));
+        }
+        Ok(colors)
+    }
+
+    fn read_gpl(text: &str) -> Result<Vec<[u8; 3]>> {
+        let mut lines = text.lines().map(str::trim);
+        if lines.next() != Some("GIMP Palette") {
+            return Err(anyhow!("missing GIMP Palette header"));
+        }
+        lines
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter(|line| !line.starts_with("Name:") && !line.starts_with("Columns:"))
+            .map(parse_triplet)
+            .collect()
+    }
+
+    /// Cycle ranges from the comments written by `write_gpl`
+    fn read_gpl_cycles(text: &str) -> Vec<(String, Range<usize>)> {
+        text.lines()
+            .filter_map(|line| {
+                let (name, range) = line.trim().strip_prefix("# Cycle ")?.rsplit_once(": ")?;
+                let (first, last) = range.split_once('-')?;
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                Some((name.to_string(), first..last + 1))
+            })
+            .collect()
+    }
+
+    /// Samples the center of every cell of a 16x16 grid preview
+    fn read_png(path: &Path, data: &[u8]) -> Result<Vec<[u8; 3]>> {
+        let img = image::load_from_memory_with_format(data, ImageFormat::Png)?.into_rgb8();
+        let (cell_w, cell_h) = (img.width() / 16, img.height() / 16);
+        if cell_w == 0 || cell_h == 0 {
+            return Err(anyhow!("{} is too small for a 16x16 palette grid", path.display()));
+        }
+        Ok((0..256)
+            .map(|i| {
+                img.get_pixel(i % 16 * cell_w + cell_w / 2, i / 16 * cell_h + cell_h / 2)
+                    .0
+            })
+            .collect())
+    }
+
+    /// Writes the palette, returning the path of the cycle range sidecar if one was needed because
+    /// the format has no room for them
+    pub fn write(&self, target: &OutputTarget) -> Result<Option<PathBuf>> {
+        let format = target.format()?;
+        let mut file = BufWriter::new(File::create(&target.path)?);
+        match format {
+            PaletteFormat::Jasc => self.write_jasc(&mut file)?,
+            PaletteFormat::Raw => self.write_raw(&mut file)?,
+            PaletteFormat::Gpl => self.write_gpl(&mut file)?,
+            PaletteFormat::Hex => self.write_hex(&mut file)?,
+            PaletteFormat::Png => self.write_png(&mut file)?,
+        }
+        file.flush()?;
+        if format == PaletteFormat::Gpl || self.cycles.is_empty() {
+            return Ok(None);
+        }
+        let sidecar = cycles_path(&target.path);
+        let cycles: Vec<_> = self
+            .cycles
+            .iter()
+            .map(|(name, range)| CycleRange {
+                name: name.clone(),
+                first: range.start,
+                last: range.end - 1,
+            })
+            .collect();
+        fs::write(&sidecar, serde_json::to_string_pretty(&cycles)?)?;
+        Ok(Some(sidecar))
+    }
+
+    fn write_jasc(&self, out: &mut impl Write) -> Result<()> {
+        write!(out, "JASC-PAL\r\n0100\r\n{}\r\n", self.colors.len())?;
+        for color in self.colors.iter() {
+            write!(out, "{} {} {}\r\n", color[0], color[1], color[2])?;
+        }
+        Ok(())
+    }
+
+    fn write_raw(&self, out: &mut impl Write) -> Result<()> {
+        for i in 0..256 {
+            out.write_all(&self.color(i))?;
+        }
+        Ok(())
+    }
+
+    fn write_gpl(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(out, "GIMP Palette\nName: palcalc\nColumns: 16\n#")?;
+        for (name, range) in self.cycles.iter() {
+            writeln!(out, "# Cycle {}: {}-{}", name, range.start, range.end - 1)?;
+        }
+        for (i, color) in self.colors.iter().enumerate() {
+            writeln!(out, "{:>3} {:>3} {:>3}\tIndex {}", color[0], color[1], color[2], i)?;
+        }
+        Ok(())
+    }
+
+    fn write_hex(&self, out: &mut impl Write) -> Result<()> {
+        for color in self.colors.iter() {
+            writeln!(out, "{:02x}{:02x}{:02x}", color[0], color[1], color[2])?;
+        }
+        Ok(())
+    }
+
+    fn write_png(&self, out: &mut (impl Write + Seek)) -> Result<()> {
+        const CELL: u32 = 16;
+        let mut img = RgbImage::new(16 * CELL, 16 * CELL);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let index = (y / CELL * 16 + x / CELL) as usize;
+            *pixel = Rgb(self.color(index));
+        }
+        img.write_to(out, ImageFormat::Png)?;
+        Ok(())
+    }
+}
+
+/// Cycle range as stored in the JSON sidecar, with inclusive indices like the GPL comments
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CycleRange {
+    name: String,
+    first: usize,
+    last: usize,
+}
+
+/// Sidecar file next to a palette holding its cycle ranges, for formats that can't carry them
+pub fn cycles_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".cycles.json");
+    PathBuf::from(name)
+}
+
+fn read_cycles(path: &Path) -> Result<Vec<(String, Range<usize>)>> {
+    let cycles: Vec<CycleRange> = serde_json::from_slice(&fs::read(path)?)?;
+    cycles
+        .into_iter()
+        .map(|cycle| {
+            if cycle.first > cycle.last {
+                return Err(anyhow!("cycle {} ends before it starts", cycle.name));
+            }
+            Ok((cycle.name, cycle.first..cycle.last + 1))
+        })
+        .collect()
+}
+
+/// Up to 256 RGB triplets
+fn read_raw(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if !data.len().is_multiple_of(3) {
+        return Err(anyhow!("raw palette size {} isn't a multiple of 3", data.len()));
+    }
+    Ok(data.chunks_exact(3).take(256).map(|c| [c[0], c[1], c[2]]).collect())
+}
+
+/// `len` bytes at `start`, or an error if the data ends before
+fn bytes(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    data.get(start..start + len)
+        .ok_or_else(|| anyhow!("truncated palette data"))
+}
+
+fn u16_le(data: &[u8], at: usize) -> Result<usize> {
+    let b = bytes(data, at, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+}
+
+fn u32_le(data: &[u8], at: usize) -> Result<usize> {
+    let b = bytes(data, at, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+/// `count` colors of `stride` bytes each, stored as RGB or as BGR
+fn read_table(data: &[u8], start: usize, count: usize, stride: usize, bgr: bool) -> Result<Vec<[u8; 3]>> {
+    Ok(bytes(data, start, count * stride)?
+        .chunks_exact(stride)
+        .map(|c| if bgr { [c[2], c[1], c[0]] } else { [c[0], c[1], c[2]] })
+        .collect())
+}
+
+/// Microsoft RIFF palette: a "data" chunk with a version, a count and RGBX entries
+fn read_riff(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if bytes(data, 8, 4)? != b"PAL " {
+        return Err(anyhow!("not a RIFF palette"));
+    }
+    let mut at = 12;
+    loop {
+        let size = u32_le(data, at + 4)?;
+        if bytes(data, at, 4)? == b"data" {
+            let count = u16_le(data, at + 10)?;
+            return read_table(data, at + 12, count, 4, false);
+        }
+        at += 8 + size + size % 2;
+    }
+}
+
+/// Adobe color table: 256 RGB triplets, optionally followed by the color count and the
+/// transparent index as big endian 16 bit values
+fn read_act(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    let mut colors = read_table(data, 0, 256, 3, false)?;
+    if let Ok(count) = bytes(data, 768, 2) {
+        let count = u16::from_be_bytes([count[0], count[1]]) as usize;
+        if count > 0 {
+            colors.truncate(count);
+        }
+    }
+    Ok(colors)
+}
+
+/// Palette of an indexed PNG, None for other color types
+fn read_png_plte(data: &[u8]) -> Result<Option<Vec<[u8; 3]>>> {
+    let reader = png::Decoder::new(data).read_info()?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return Ok(None);
+    }
+    let palette = info
+        .palette
+        .as_deref()
+        .ok_or_else(|| anyhow!("indexed PNG without palette"))?;
+    Ok(Some(read_table(palette, 0, palette.len() / 3, 3, false)?))
+}
+
+/// Global color table of a GIF
+fn read_gif(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if !data.starts_with(b"GIF") {
+        return Err(anyhow!("not a GIF image"));
+    }
+    let flags = bytes(data, 10, 1)?[0];
+    if flags & 0x80 == 0 {
+        return Err(anyhow!("GIF without global color table"));
+    }
+    read_table(data, 13, 2 << (flags & 0x07), 3, false)
+}
+
+/// Color table of a BMP with up to 8 bits per pixel
+fn read_bmp(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if !data.starts_with(b"BM") {
+        return Err(anyhow!("not a BMP image"));
+    }
+    let header = u32_le(data, 14)?;
+    // OS/2 core headers store 16 bit sizes and BGR entries, later ones BGRX entries and a color count
+    let (bits, stride, used) = if header == 12 {
+        (u16_le(data, 24)?, 3, 0)
+    } else {
+        (u16_le(data, 28)?, 4, u32_le(data, 46)?)
+    };
+    if bits > 8 {
+        return Err(anyhow!("{} bit BMP has no palette", bits));
+    }
+    let count = if used > 0 { used.min(256) } else { 1 << bits };
+    read_table(data, 14 + header, count, stride, true)
+}
+
+/// Palette of a PCX, either the 256 colors after the image data or the 16 in the header
+fn read_pcx(data: &[u8]) -> Result<Vec<[u8; 3]>> {
+    if bytes(data, 0, 1)? != [0x0a] {
+        return Err(anyhow!("not a PCX image"));
+    }
+    let bits = bytes(data, 3, 1)?[0] as u32 * bytes(data, 65, 1)?[0] as u32;
+    match bits {
+        8 => {
+            let start = data
+                .len()
+                .checked_sub(769)
+                .ok_or_else(|| anyhow!("truncated palette data"))?;
+            if data[start] != 0x0c {
+                return Err(anyhow!("PCX without 256 color palette"));
+            }
+            read_table(data, start + 1, 256, 3, false)
+        }
+        1..=4 => read_table(data, 16, 1 << bits, 3, false),
+        _ => Err(anyhow!("{} bit PCX has no palette", bits)),
+    }
+}
+
+/// Parses the first three whitespace separated values of a line as RGB
+fn parse_triplet(line: &str) -> Result<[u8; 3]> {
+    let mut values = line.split_whitespace().map(str::parse::<u8>);
+    let mut next = || -> Result<u8> {
+        values
+            .next()
+            .ok_or_else(|| anyhow!("expected R G B, got \"{}\"", line))?
+            .map_err(|e| anyhow!(e))
+    };
+    Ok([next()?, next()?, next()?])
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TintRamp {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: [u8; 3],
+    pub steps: u32,
+    /// Tint amount of the last step, from 0 to 1
+    pub max: f64,
+}
+
+impl TintRamp {
+    /// Damage, bonus and radiation suit ramps of Doom's PLAYPAL
+    pub fn doom() -> Vec<TintRamp> {
+        vec![
+            TintRamp {
+                name: "damage".to_string(),
+                color: [255, 0, 0],
+                steps: 8,
+                max: 8.0 / 9.0,
+            },
+            TintRamp {
+                name: "bonus".to_string(),
+                color: [215, 186, 69],
+                steps: 4,
+                max: 0.5,
+            },
+            TintRamp {
+                name: "radiation".to_string(),
+                color: [0, 255, 0],
+                steps: 1,
+                max: 0.125,
+            },
+        ]
+    }
+}
+
+/// Stack of tinted palettes written as one lump, the untinted palette comes first
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaypalTarget {
+    pub path: PathBuf,
+    pub preview: Option<PathBuf>,
+    /// Defaults to Doom's damage, bonus and radiation suit ramps
+    #[serde(default = "TintRamp::doom")]
+    pub ramps: Vec<TintRamp>,
+}
+
+impl PlaypalTarget {
+    pub fn new(path: PathBuf) -> PlaypalTarget {
+        PlaypalTarget {
+            preview: Some(path.with_extension("png")),
+            path,
+            ramps: TintRamp::doom(),
+        }
+    }
+
+    /// Names and step counts of the ramps, e.g. "damage 8, bonus 4"
+    pub fn describe(&self) -> String {
+        let ramps: Vec<String> = self
+            .ramps
+            .iter()
+            .map(|ramp| format!("{} {}", ramp.name, ramp.steps))
+            .collect();
+        ramps.join(", ")
+    }
+
+    pub fn build(&self, palette: &Palette) -> Vec<Palette> {
+        let mut palettes = vec![palette.tinted([0, 0, 0], 0.0)];
+        for ramp in self.ramps.iter() {
+            for step in 1..=ramp.steps {
+                palettes.push(palette.tinted(ramp.color, ramp.max * step as f64 / ramp.steps as f64));
+            }
+        }
+        palettes
+    }
+
+    /// Writes the palettes and returns how many were written
+    pub fn write(&self, palette: &Palette) -> Result<usize> {
+        let palettes = self.build(palette);
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        for variant in palettes.iter() {
+            variant.write_raw(&mut file)?;
+        }
+        file.flush()?;
+
+        if let Some(preview) = &self.preview {
+            // One 8 pixel high row per palette
+            let img = RgbImage::from_fn(256, palettes.len() as u32 * 8, |x, y| {
+                Rgb(palettes[(y / 8) as usize].color(x as usize))
+            });
+            img.save(preview)?;
+        }
+        Ok(palettes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLORS: [[u8; 3]; 3] = [[255, 0, 0], [0, 128, 0], [10, 20, 30]];
+
+    fn decode(name: &str, data: &[u8]) -> Vec<[u8; 3]> {
+        Palette::decode(Path::new(name), data).unwrap().colors
+    }
+
+    #[test]
+    fn text_formats() {
+        let jasc = "\u{feff}JASC-PAL\r\n0100\r\n3\r\n255 0 0\r\n0 128 0\r\n10 20 30\r\n";
+        assert_eq!(decode("a.pal", jasc.as_bytes()), COLORS);
+        let gpl = "GIMP Palette\nName: test\nColumns: 16\n# Cycle water: 1-2\n\
+                   255   0   0\tRed: bright\n  0 128   0\n 10  20  30\tIndex 2\n";
+        let palette = Palette::decode(Path::new("a.gpl"), gpl.as_bytes()).unwrap();
+        assert_eq!(palette.colors, COLORS);
+        assert_eq!(palette.cycles, [("water".to_string(), 1..3)]);
+        assert_eq!(decode("a.hex", b"ff0000\n008000\n\n0a141e\n"), COLORS);
+        let too_many = "000000\n".repeat(MAX_COLORS + 1);
+        assert!(Palette::decode(Path::new("a.hex"), too_many.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn raw_and_act() {
+        let raw: Vec<u8> = COLORS.concat();
+        assert_eq!(decode("a.lmp", &raw), COLORS);
+        // A PLAYPAL stacks 14 palettes, only the first is used
+        assert_eq!(decode("PLAYPAL", &raw.repeat(200)).len(), 256);
+        assert!(Palette::decode(Path::new("a.raw"), &raw[..8]).is_err());
+
+        let mut act = raw.clone();
+        act.resize(768, 0);
+        assert_eq!(decode("a.act", &act).len(), 256);
+        act.extend([0, 3, 0xff, 0xff]);
+        assert_eq!(decode("a.act", &act), COLORS);
+    }
+
+    #[test]
+    fn riff() {
+        let mut data = b"RIFF\0\0\0\0PAL ".to_vec();
+        // Chunks before the data chunk are skipped, including the pad byte of odd sizes
+        data.extend(b"note\x03\0\0\0abc\0");
+        data.extend(b"data");
+        data.extend((4 + 4 * COLORS.len() as u32).to_le_bytes());
+        data.extend([0, 3, COLORS.len() as u8, 0]);
+        for [r, g, b] in COLORS {
+            data.extend([r, g, b, 0]);
+        }
+        assert_eq!(decode("a.pal", &data), COLORS);
+    }
+
+    #[test]
+    fn pcx() {
+        let mut header = vec![0; 128];
+        header[0] = 0x0a;
+        header[3] = 8;
+        header[65] = 1;
+        let mut data = header.clone();
+        data.extend([0xc1, 0x00, 0x0c]);
+        data.push(0x0c);
+        let mut colors = COLORS.concat();
+        colors.resize(768, 0);
+        data.extend(&colors);
+        assert_eq!(&decode("a.pcx", &data)[..3], COLORS);
+
+        // 4 planes of 1 bit keep their 16 colors in the header
+        header[3] = 1;
+        header[65] = 4;
+        header[16..16 + 9].copy_from_slice(&COLORS.concat());
+        let colors = decode("a.pcx", &header);
+        assert_eq!(colors.len(), 16);
+        assert_eq!(&colors[..3], COLORS);
+    }
+
+    #[test]
+    fn bmp() {
+        // Windows header with a color count and BGRX entries
+        let mut data = b"BM".to_vec();
+        data.resize(14, 0);
+        let mut info = vec![0; 40];
+        info[..4].copy_from_slice(&40u32.to_le_bytes());
+        info[14..16].copy_from_slice(&8u16.to_le_bytes());
+        info[32..36].copy_from_slice(&3u32.to_le_bytes());
+        data.extend(info);
+        for [r, g, b] in COLORS {
+            data.extend([b, g, r, 0]);
+        }
+        assert_eq!(decode("a.bmp", &data), COLORS);
+
+        // OS/2 core header with BGR entries for every possible index
+        let mut data = b"BM".to_vec();
+        data.resize(14, 0);
+        data.extend(12u32.to_le_bytes());
+        data.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        for [r, g, b] in [COLORS[0], COLORS[1]] {
+            data.extend([b, g, r]);
+        }
+        assert_eq!(decode("a.bmp", &data), COLORS[..2]);
+    }
+
+    #[test]
+    fn gif() {
+        let mut data = b"GIF89a".to_vec();
+        data.extend([1, 0, 1, 0, 0x81, 0, 0]);
+        data.extend(COLORS.concat());
+        data.extend([0; 3]);
+        assert_eq!(&decode("a.gif", &data)[..3], COLORS);
+        data[10] = 0;
+        assert!(Palette::decode(Path::new("a.gif"), &data).is_err());
+    }
+
+    #[test]
+    fn indexed_png() {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(COLORS.concat());
+        encoder.write_header().unwrap().write_image_data(&[0, 2]).unwrap();
+        assert_eq!(decode("a.png", &data), COLORS);
+    }
+}
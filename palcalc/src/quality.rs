@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::{
+    imageops::{self, FilterType},
+    ImageFormat, Rgba, RgbaImage,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    colors::{self, FloatColor, Lab},
+    heatmap::HeatmapConfig,
+    histogram::{AlphaOptions, Group, InputFile},
+    inputs,
+    palette::Palette,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportSort {
+    /// Highest mean squared error first
+    #[default]
+    Mse,
+    /// Lowest peak signal-to-noise ratio first
+    Psnr,
+    /// Highest single pixel error first
+    Max,
+    /// Most pixels above the error threshold first
+    Above,
+    /// Highest mean CIEDE2000 difference first
+    #[value(name = "delta-e")]
+    #[serde(rename = "delta-e")]
+    DeltaE,
+    /// Lowest structural similarity first
+    Ssim,
+    /// By file path
+    Name,
+}
+
+pub fn default_threshold() -> f64 {
+    16.0
+}
+
+fn default_top() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportTarget {
+    pub path: PathBuf,
+    pub format: Option<ReportFormat>,
+    #[serde(default)]
+    pub sort: ReportSort,
+    /// Pixel error in 8-bit RGB units above which a pixel counts as poorly represented
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+    /// Number of worst files shown after the run
+    #[serde(default = "default_top")]
+    pub top: usize,
+}
+
+/// Error of one input image after mapping its pixels to the nearest palette colors
+#[derive(Debug, Serialize)]
+pub struct ImageQuality {
+    pub path: PathBuf,
+    pub group: Group,
+    pub pixels: f64,
+    /// Mean squared error per channel, in 8-bit units
+    pub mse: f64,
+    /// Peak signal-to-noise ratio in dB, `MAX_PSNR` for an exact match
+    pub psnr: f64,
+    /// Largest distance between a pixel and its palette color, in 8-bit RGB units
+    pub max_error: f64,
+    /// Percentage of pixels whose error exceeds the threshold
+    pub above_threshold: f64,
+    /// Mean CIEDE2000 difference between each pixel and its palette color
+    pub delta_e: f64,
+    /// Structural similarity of the luma of the original and the remapped image, 1 for identical images
+    pub ssim: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heatmap: Option<PathBuf>,
+    #[serde(skip)]
+    pub thumbnails: Option<Thumbnails>,
+}
+
+/// PNG encoded previews of an image before and after mapping to the palette
+#[derive(Debug)]
+pub struct Thumbnails {
+    pub original: Vec<u8>,
+    pub remapped: Vec<u8>,
+}
+
+/// Side of the square windows SSIM is computed over, windows overlap by half
+const SSIM_WINDOW: usize = 8;
+/// Upper bound for the reported PSNR in dB, since an exact match would be infinite and JSON can't
+/// represent that
+const MAX_PSNR: f64 = 99.0;
+
+/// Mean structural similarity of two luma planes with values from 0 to 255
+fn ssim(original: &[f64], remapped: &[f64], width: usize, height: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (window_w, window_h) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let starts = |size: usize, window: usize| -> Vec<usize> {
+        let mut starts: Vec<usize> = (0..=size - window).step_by((window / 2).max(1)).collect();
+        if starts.last() != Some(&(size - window)) {
+            starts.push(size - window);
+        }
+        starts
+    };
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for &y0 in starts(height, window_h).iter() {
+        for &x0 in starts(width, window_w).iter() {
+            let n = (window_w * window_h) as f64;
+            let pixels = (y0..y0 + window_h).flat_map(|y| (x0..x0 + window_w).map(move |x| y * width + x));
+            let (mut sx, mut sy, mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for i in pixels {
+                let (x, y) = (original[i], remapped[i]);
+                sx += x;
+                sy += y;
+                sxx += x * x;
+                syy += y * y;
+                sxy += x * y;
+            }
+            let (mx, my) = (sx / n, sy / n);
+            let (vx, vy, cov) = (sxx / n - mx * mx, syy / n - my * my, sxy / n - mx * my);
+            total += ((2.0 * mx * my + C1) * (2.0 * cov + C2)) / ((mx * mx + my * my + C1) * (vx + vy + C2));
+            windows += 1;
+        }
+    }
+    total / windows.max(1) as f64
+}
+
+/// Palette color a pixel maps to, with its RGB and CIEDE2000 error
+struct Mapping {
+    color: FloatColor,
+    rgb: [u8; 3],
+    error: f64,
+    delta_e: f64,
+}
+
+impl ImageQuality {
+    pub fn measure(
+        file: &InputFile,
+        palette: &[FloatColor],
+        palette_lab: &[Lab],
+        alpha: &AlphaOptions,
+        options: &MeasureOptions,
+        heatmap: Option<&Path>,
+    ) -> Result<ImageQuality> {
+        let threshold = options.threshold;
+        let heatmap = options.heatmaps.zip(heatmap);
+        let img = inputs::open_image(&file.path)?.to_rgba8();
+        let mut remapped = options.thumbnails.map(|_| img.clone());
+        let mut errors = Vec::with_capacity(if heatmap.is_some() { img.len() / 4 } else { 0 });
+        let mut mappings: HashMap<[u8; 3], Mapping> = HashMap::new();
+        let (mut pixels, mut squared, mut max_error, mut above, mut delta_e) = (0.0, 0.0, 0.0f64, 0.0, 0.0);
+        let mut original_luma = Vec::with_capacity(img.len() / 4);
+        let mut remapped_luma = Vec::with_capacity(img.len() / 4);
+        for (i, pixel) in img.pixels().enumerate() {
+            let rgb = [pixel[0], pixel[1], pixel[2]];
+            let color = FloatColor::from_rgb8(rgb);
+            original_luma.push(color.luminance() * 255.0);
+            let weight = alpha.pixel_weight(pixel.0);
+            if weight <= 0.0 {
+                // Skipped pixels stay as they are in the remapped image
+                remapped_luma.push(color.luminance() * 255.0);
+                if heatmap.is_some() {
+                    errors.push(None);
+                }
+                continue;
+            }
+            let mapping = mappings.entry(rgb).or_insert_with(|| {
+                let index = colors::nearest(palette, color);
+                Mapping {
+                    color: palette[index],
+                    rgb: palette[index].to_rgb8(),
+                    error: color.distance(palette[index]) * 255.0,
+                    delta_e: Lab::from_rgb(color).delta_e(palette_lab[index]),
+                }
+            });
+            remapped_luma.push(mapping.color.luminance() * 255.0);
+            if let Some(remapped) = remapped.as_mut() {
+                let [r, g, b] = mapping.rgb;
+                let (x, y) = (i as u32 % img.width(), i as u32 / img.width());
+                remapped.put_pixel(x, y, Rgba([r, g, b, pixel[3]]));
+            }
+            if heatmap.is_some() {
+                errors.push(Some(mapping.error));
+            }
+            pixels += weight;
+            squared += mapping.error * mapping.error * weight;
+            delta_e += mapping.delta_e * weight;
+            max_error = max_error.max(mapping.error);
+            if mapping.error > threshold {
+                above += weight;
+            }
+        }
+
+        if let Some((config, path)) = heatmap {
+            config.write(path, img.width(), img.height(), &errors, &original_luma)?;
+        }
+
+        let mse = if pixels > 0.0 { squared / pixels / 3.0 } else { 0.0 };
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        Ok(ImageQuality {
+            path: file.path.clone(),
+            group: file.group,
+            pixels,
+            mse,
+            psnr: (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR),
+            max_error,
+            above_threshold: if pixels > 0.0 { above * 100.0 / pixels } else { 0.0 },
+            delta_e: if pixels > 0.0 { delta_e / pixels } else { 0.0 },
+            ssim: ssim(&original_luma, &remapped_luma, width, height),
+            heatmap: heatmap.map(|(_, path)| path.to_path_buf()),
+            thumbnails: match (options.thumbnails, remapped) {
+                (Some(size), Some(remapped)) => Some(Thumbnails {
+                    original: thumbnail(&img, size)?,
+                    remapped: thumbnail(&remapped, size)?,
+                }),
+                _ => None,
+            },
+        })
+    }
+}
+
+pub struct MeasureOptions<'a> {
+    /// Pixel error above which a pixel counts as poorly represented
+    pub threshold: f64,
+    pub heatmaps: Option<&'a HeatmapConfig>,
+    /// Keep before and after thumbnails fitting into this many pixels
+    pub thumbnails: Option<u32>,
+}
+
+/// Measures every input file in input order, writing heatmaps if configured
+pub fn measure_all(
+    files: &[InputFile],
+    palette: &Palette,
+    alpha: &AlphaOptions,
+    options: &MeasureOptions,
+) -> Result<Vec<ImageQuality>> {
+    let colors = palette.float_colors();
+    let lab: Vec<Lab> = colors.iter().map(|&color| Lab::from_rgb(color)).collect();
+    let paths = options.heatmaps.map(|config| config.paths(files)).unwrap_or_default();
+    files
+        .par_iter()
+        .enumerate()
+        .map(|(i, file)| ImageQuality::measure(file, &colors, &lab, alpha, options, paths.get(i).map(PathBuf::as_path)))
+        .collect()
+}
+
+/// Scales an image to fit into `size` pixels, with hard pixel edges when enlarging
+fn thumbnail(img: &RgbaImage, size: u32) -> Result<Vec<u8>> {
+    let scale = size as f64 / img.width().max(img.height()) as f64;
+    let (width, height) = (
+        ((img.width() as f64 * scale).round() as u32).max(1),
+        ((img.height() as f64 * scale).round() as u32).max(1),
+    );
+    let filter = if scale >= 1.0 {
+        FilterType::Nearest
+    } else {
+        FilterType::Triangle
+    };
+    let mut data = Cursor::new(vec![]);
+    imageops::resize(img, width, height, filter).write_to(&mut data, ImageFormat::Png)?;
+    Ok(data.into_inner())
+}
+
+/// Quotes a CSV field if it contains separators or quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl ReportTarget {
+    pub fn new(path: PathBuf) -> ReportTarget {
+        ReportTarget {
+            path,
+            format: None,
+            sort: ReportSort::default(),
+            threshold: default_threshold(),
+            top: default_top(),
+        }
+    }
+
+    pub fn format(&self) -> Result<ReportFormat> {
+        if let Some(format) = self.format {
+            return Ok(format);
+        }
+        match self
+            .path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            Some("csv") => Ok(ReportFormat::Csv),
+            Some("json") => Ok(ReportFormat::Json),
+            _ => Err(anyhow!("can't guess report format of {}", self.path.display())),
+        }
+    }
+
+    pub fn sort(&self, report: &mut [ImageQuality]) {
+        report.sort_by(|a, b| match self.sort {
+            ReportSort::Mse => b.mse.total_cmp(&a.mse),
+            ReportSort::Psnr => a.psnr.total_cmp(&b.psnr),
+            ReportSort::Max => b.max_error.total_cmp(&a.max_error),
+            ReportSort::Above => b.above_threshold.total_cmp(&a.above_threshold),
+            ReportSort::DeltaE => b.delta_e.total_cmp(&a.delta_e),
+            ReportSort::Ssim => a.ssim.total_cmp(&b.ssim),
+            ReportSort::Name => a.path.cmp(&b.path),
+        });
+    }
+
+    pub fn write(&self, report: &[ImageQuality]) -> Result<()> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        match self.format()? {
+            ReportFormat::Csv => write_csv(&mut out, report)?,
+            ReportFormat::Json => serde_json::to_writer_pretty(&mut out, report)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn write_csv(out: &mut impl Write, report: &[ImageQuality]) -> Result<()> {
+    writeln!(out, "path,group,pixels,mse,psnr,max_error,above_threshold,delta_e,ssim")?;
+    for quality in report {
+        writeln!(
+            out,
+            "{},{},{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4}",
+            csv_field(&quality.path.to_string_lossy()),
+            quality.group.name(),
+            quality.pixels,
+            quality.mse,
+            quality.psnr,
+            quality.max_error,
+            quality.above_threshold,
+            quality.delta_e,
+            quality.ssim
+        )?;
+    }
+    Ok(())
+}
+
+/// Display path relative to the current directory where possible
+fn short_path(path: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Table rows for the worst files, as shown in the result block
+pub fn summary_rows(report: &[ImageQuality], top: usize) -> Vec<Vec<String>> {
+    report
+        .iter()
+        .take(top)
+        .map(|quality| {
+            vec![
+                short_path(&quality.path),
+                format!("{:.2}", quality.mse),
+                format!("{:.2}", quality.psnr),
+                format!("{:.2}", quality.max_error),
+                format!("{:.1}%", quality.above_threshold),
+                format!("{:.2}", quality.delta_e),
+                format!("{:.4}", quality.ssim),
+            ]
+        })
+        .collect()
+}
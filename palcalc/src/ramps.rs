@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::{
+    colorcalc::{self, CalcOptions, ColorCalc, ColorData},
+    colors::{self, FloatColor},
+    interface::{StatusCalculating, Tui},
+    palette::Palette,
+};
+
+/// Palette laid out as `rows` hue ramps of `length` shades each, dark to light
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RampLayout {
+    pub rows: u32,
+    pub length: u32,
+}
+
+pub fn parse_layout(s: &str) -> Result<RampLayout> {
+    let (rows, length) = s
+        .split_once('x')
+        .ok_or_else(|| anyhow!("expected ROWSxLENGTH, got \"{}\"", s))?;
+    Ok(RampLayout {
+        rows: rows.parse()?,
+        length: length.parse()?,
+    })
+}
+
+/// Brightness-independent part of a color, its channels sum to 1
+fn chromaticity(color: FloatColor) -> Option<FloatColor> {
+    let sum = color.r + color.g + color.b;
+    if sum <= 0.0 {
+        return None;
+    }
+    Some(FloatColor {
+        r: color.r / sum,
+        g: color.g / sum,
+        b: color.b / sum,
+    })
+}
+
+/// Color with the given chromaticity and luminance. Where that falls outside the RGB cube the color
+/// is desaturated towards the gray of the same luminance instead of clipped, so bright steps stay
+/// distinct and approach white rather than piling up on it.
+fn with_luminance(chroma: FloatColor, luminance: f64) -> FloatColor {
+    let luminance = luminance.clamp(0.0, 1.0);
+    let scale = luminance / chroma.luminance().max(f64::EPSILON);
+    let color = FloatColor {
+        r: chroma.r * scale,
+        g: chroma.g * scale,
+        b: chroma.b * scale,
+    };
+    let max = color.r.max(color.g).max(color.b);
+    if max <= 1.0 {
+        return color;
+    }
+    // The weights of the luminance sum to 1, so mixing with gray keeps the luminance
+    let gray = FloatColor {
+        r: luminance,
+        g: luminance,
+        b: luminance,
+    };
+    gray.mix(color, (1.0 - luminance) / (max - luminance))
+}
+
+struct Ramp {
+    chroma: FloatColor,
+    max_luminance: f64,
+}
+
+impl Ramp {
+    /// Evenly spaced steps from near black up to the brightest color of the group
+    fn shades(&self, length: u32) -> Vec<[u8; 3]> {
+        (1..=length)
+            .map(|i| with_luminance(self.chroma, self.max_luminance * i as f64 / length as f64).to_rgb8())
+            .collect()
+    }
+}
+
+/// Clusters the adjustable colors into hue/chroma groups and turns each group
+/// into a ramp of evenly spaced luminance steps. Fixed colors come first.
+pub fn calculate(
+    layout: &RampLayout,
+    options: &CalcOptions,
+    adjustable_colors: ColorData,
+    fixed_colors: ColorData,
+    reserved: &[[u8; 3]],
+    tui: &mut Tui,
+) -> Result<Palette> {
+    let fixed = colorcalc::fixed_list(&fixed_colors, reserved);
+    drop(fixed_colors);
+    let needed = fixed.len() + (layout.rows * layout.length) as usize;
+    if needed > options.colors as usize {
+        return Err(anyhow!(
+            "{} fixed colors and {}x{} ramps don't fit into a palette of {} colors",
+            fixed.len(),
+            layout.rows,
+            layout.length,
+            options.colors
+        ));
+    }
+
+    let colors: Vec<(FloatColor, f64)> = adjustable_colors
+        .colors()
+        .into_iter()
+        .map(|(color, count)| (FloatColor::from_rgb8(color), count))
+        .collect();
+    drop(adjustable_colors);
+
+    // Dark colors have unreliable hue, so they weigh less when grouping
+    let points = colors
+        .iter()
+        .filter_map(|&(color, count)| Some((chromaticity(color)?, count * color.luminance())))
+        .filter(|&(_, weight)| weight > 0.0)
+        .collect();
+    let group_options = CalcOptions {
+        colors: layout.rows,
+        ..*options
+    };
+    let mut calculator = ColorCalc::from_points(&group_options, points, &[])?;
+    let mut status = StatusCalculating::new(
+        tui,
+        "Calculating hue groups",
+        options.attempts,
+        options.max_steps,
+        calculator.unique_colors,
+        fixed.len() as u64,
+    )?;
+    calculator.run(&mut status, tui)?;
+
+    let chromas = calculator.best_centroids().to_vec();
+    let mut ramps: Vec<Ramp> = chromas
+        .iter()
+        .map(|&chroma| Ramp {
+            chroma,
+            max_luminance: 0.0,
+        })
+        .collect();
+    for &(color, _) in colors.iter() {
+        let Some(chroma) = chromaticity(color) else {
+            continue;
+        };
+        let ramp = &mut ramps[colors::nearest(&chromas, chroma)];
+        ramp.max_luminance = ramp.max_luminance.max(color.luminance());
+    }
+    ramps.retain(|ramp| ramp.max_luminance > 0.0);
+    // Grays first, then by hue
+    ramps.sort_by(|a, b| {
+        let key = |ramp: &Ramp| (ramp.chroma.saturation() > 0.05, ramp.chroma.hue());
+        key(a).partial_cmp(&key(b)).unwrap()
+    });
+
+    let fixed_count = fixed.len();
+    let mut palette = fixed;
+    for ramp in ramps.iter() {
+        palette.extend(ramp.shades(layout.length));
+    }
+    Ok(Palette::new(palette).lock_first(fixed_count))
+}
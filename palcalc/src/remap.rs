@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+
+use crate::{
+    colors::{self, FloatColor},
+    histogram::{AlphaOptions, InputFile},
+    inputs,
+    palette::Palette,
+};
+
+/// Maps images to the nearest entries of a fixed palette and writes them as indexed PNGs
+pub struct Remapper<'a> {
+    palette: &'a Palette,
+    alpha: &'a AlphaOptions,
+    /// Index written for skipped pixels, never used for visible ones
+    transparent: Option<u8>,
+    /// Entries visible pixels may map to, with their palette index
+    candidates: Vec<(usize, FloatColor)>,
+}
+
+impl<'a> Remapper<'a> {
+    pub fn new(palette: &'a Palette, alpha: &'a AlphaOptions, transparent: Option<u8>) -> Result<Remapper<'a>> {
+        if let Some(index) = transparent {
+            if index as usize >= palette.colors.len() {
+                return Err(anyhow!(
+                    "transparent index {} is outside the palette of {} colors",
+                    index,
+                    palette.colors.len()
+                ));
+            }
+        }
+        let candidates = palette
+            .float_colors()
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != transparent.map(usize::from))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(anyhow!("the palette has no entries left for visible pixels"));
+        }
+        Ok(Remapper {
+            palette,
+            alpha,
+            transparent,
+            candidates,
+        })
+    }
+
+    /// Remaps every file into `dir`, returning the written paths in input order
+    pub fn write_all(&self, files: &[InputFile], dir: &Path) -> Result<Vec<PathBuf>> {
+        fs::create_dir_all(dir)?;
+        let paths = inputs::output_paths(files.iter().map(|file| file.path.as_path()), dir, ".png");
+        files
+            .par_iter()
+            .zip(paths.par_iter())
+            .map(|(file, path)| {
+                self.write(&file.path, path)?;
+                Ok(path.clone())
+            })
+            .collect()
+    }
+
+    fn write(&self, input: &Path, output: &Path) -> Result<()> {
+        let img = inputs::open_image(input)?.to_rgba8();
+        let colors: Vec<FloatColor> = self.candidates.iter().map(|&(_, color)| color).collect();
+        let mut nearest: HashMap<[u8; 3], u8> = HashMap::new();
+        let data: Vec<u8> = img
+            .pixels()
+            .map(|pixel| match self.transparent {
+                Some(index) if self.alpha.pixel_weight(pixel.0) <= 0.0 => index,
+                _ => *nearest.entry([pixel[0], pixel[1], pixel[2]]).or_insert_with(|| {
+                    let color = FloatColor::from_rgb8([pixel[0], pixel[1], pixel[2]]);
+                    self.candidates[colors::nearest(&colors, color)].0 as u8
+                }),
+            })
+            .collect();
+
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(output)?), img.width(), img.height());
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.palette.colors.concat());
+        if let Some(index) = self.transparent {
+            let mut trns = vec![255; index as usize + 1];
+            trns[index as usize] = 0;
+            encoder.set_trns(trns);
+        }
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
+    }
+}
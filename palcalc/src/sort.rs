@@ -0,0 +1,118 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{colors::FloatColor, palette::Palette};
+
+/// Colors with less saturation than this sort as grays
+const GRAY_SATURATION: f64 = 0.05;
+/// Number of hue buckets colors are grouped into before sorting by luminance
+const HUE_BUCKETS: f64 = 12.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Dark to light
+    Luminance,
+    /// Grays first, then hue groups, each dark to light
+    Hue,
+    /// Most used colors first
+    Population,
+    /// Nearest-neighbor path starting at the darkest color
+    Path,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SortConfig {
+    pub order: SortOrder,
+    /// Table of 256 bytes mapping old palette indices to new ones
+    pub remap: Option<PathBuf>,
+}
+
+fn hue_key(color: FloatColor) -> (f64, f64) {
+    let bucket = if color.saturation() < GRAY_SATURATION {
+        -1.0
+    } else {
+        (color.hue() / 360.0 * HUE_BUCKETS).floor()
+    };
+    (bucket, color.luminance())
+}
+
+/// Greedy traveling-salesman path through `colors`, starting at the darkest one
+fn nearest_path(colors: &[FloatColor]) -> Vec<usize> {
+    let mut left: Vec<usize> = (0..colors.len()).collect();
+    let mut path = Vec::with_capacity(colors.len());
+    let Some(start) = (0..left.len()).min_by(|&a, &b| {
+        colors[left[a]]
+            .luminance()
+            .partial_cmp(&colors[left[b]].luminance())
+            .unwrap()
+    }) else {
+        return path;
+    };
+    let mut current = left.swap_remove(start);
+    path.push(current);
+    while !left.is_empty() {
+        let next = (0..left.len())
+            .min_by(|&a, &b| {
+                let distance = |i: usize| colors[current].distance_squared(colors[left[i]]);
+                distance(a).partial_cmp(&distance(b)).unwrap()
+            })
+            .unwrap();
+        current = left.swap_remove(next);
+        path.push(current);
+    }
+    path
+}
+
+/// Reorders the movable entries of `palette`. Locked and fullbright entries keep their index.
+/// Returns the sorted palette and a 256 entry table mapping old indices to new ones.
+pub fn sort(palette: &Palette, order: SortOrder) -> (Palette, Vec<u8>) {
+    let slots: Vec<usize> = (0..palette.colors.len())
+        .filter(|&i| !palette.locked[i] && !palette.is_fullbright(i))
+        .collect();
+    let colors: Vec<FloatColor> = slots
+        .iter()
+        .map(|&i| FloatColor::from_rgb8(palette.colors[i]))
+        .collect();
+
+    let mut sorted: Vec<usize> = (0..slots.len()).collect();
+    match order {
+        SortOrder::Luminance => {
+            sorted.sort_by(|&a, &b| colors[a].luminance().partial_cmp(&colors[b].luminance()).unwrap())
+        }
+        SortOrder::Hue => sorted.sort_by(|&a, &b| hue_key(colors[a]).partial_cmp(&hue_key(colors[b])).unwrap()),
+        SortOrder::Population => sorted.sort_by(|&a, &b| {
+            palette.population[slots[b]]
+                .partial_cmp(&palette.population[slots[a]])
+                .unwrap()
+        }),
+        SortOrder::Path => sorted = nearest_path(&colors),
+    }
+
+    let mut result = palette.clone();
+    let mut remap: Vec<u8> = (0..=255).collect();
+    for (&slot, &from) in slots.iter().zip(sorted.iter()) {
+        let old = slots[from];
+        result.colors[slot] = palette.colors[old];
+        result.population[slot] = palette.population[old];
+        if old < remap.len() {
+            remap[old] = slot as u8;
+        }
+    }
+    (result, remap)
+}
+
+impl SortConfig {
+    /// Sorts the palette and writes the remap table if one is configured
+    pub fn apply(&self, palette: &Palette) -> Result<Palette> {
+        let (sorted, remap) = sort(palette, self.order);
+        if let Some(path) = &self.remap {
+            fs::write(path, remap)?;
+        }
+        Ok(sorted)
+    }
+}
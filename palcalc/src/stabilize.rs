@@ -0,0 +1,180 @@
+use crate::{colors::FloatColor, palette::Palette};
+
+/// Cost of placing a color on an index the previous palette didn't have
+const EMPTY_SLOT_COST: f64 = 2.0;
+
+/// Minimum cost assignment of rows to columns of a square matrix (Hungarian algorithm).
+/// Returns the column assigned to every row.
+fn assignment(cost: &[Vec<f64>]) -> Vec<usize> {
+    let n = cost.len();
+    // 1-based potentials and matching, column 0 is a sentinel
+    let mut u = vec![0.0; n + 1];
+    let mut v = vec![0.0; n + 1];
+    let mut row_of = vec![0usize; n + 1];
+    let mut way = vec![0usize; n + 1];
+    for row in 1..=n {
+        row_of[0] = row;
+        let mut col = 0;
+        let mut min_to = vec![f64::MAX; n + 1];
+        let mut used = vec![false; n + 1];
+        loop {
+            used[col] = true;
+            let current = row_of[col];
+            let mut delta = f64::MAX;
+            let mut next = 0;
+            for j in 1..=n {
+                if used[j] {
+                    continue;
+                }
+                let reduced = cost[current - 1][j - 1] - u[current] - v[j];
+                if reduced < min_to[j] {
+                    min_to[j] = reduced;
+                    way[j] = col;
+                }
+                if min_to[j] < delta {
+                    delta = min_to[j];
+                    next = j;
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    u[row_of[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_to[j] -= delta;
+                }
+            }
+            col = next;
+            if row_of[col] == 0 {
+                break;
+            }
+        }
+        while col != 0 {
+            let prev = way[col];
+            row_of[col] = row_of[prev];
+            col = prev;
+        }
+    }
+
+    let mut result = vec![0; n];
+    for j in 1..=n {
+        result[row_of[j] - 1] = j - 1;
+    }
+    result
+}
+
+/// Color difference at one palette index between the previous and the new palette
+pub struct IndexChange {
+    pub index: usize,
+    pub old: [u8; 3],
+    pub new: [u8; 3],
+    /// Distance in 8-bit RGB units
+    pub distance: f64,
+}
+
+/// Moves the entries of `palette` to the indices of the closest colors in `previous`.
+/// Locked entries keep their index and fullbright entries stay inside the fullbright range.
+pub fn stabilize(palette: &Palette, previous: &Palette) -> Palette {
+    let mut result = palette.clone();
+    for fullbright in [false, true] {
+        let slots: Vec<usize> = (0..palette.colors.len())
+            .filter(|&i| !palette.locked[i] && palette.is_fullbright(i) == fullbright)
+            .collect();
+        let cost: Vec<Vec<f64>> = slots
+            .iter()
+            .map(|&from| {
+                let color = FloatColor::from_rgb8(palette.colors[from]);
+                slots
+                    .iter()
+                    .map(|&to| match previous.colors.get(to) {
+                        Some(&old) => color.distance(FloatColor::from_rgb8(old)),
+                        None => EMPTY_SLOT_COST,
+                    })
+                    .collect()
+            })
+            .collect();
+        for (from, to) in assignment(&cost).into_iter().enumerate() {
+            result.colors[slots[to]] = palette.colors[slots[from]];
+            result.population[slots[to]] = palette.population[slots[from]];
+        }
+    }
+    result
+}
+
+/// Indices whose color differs from the previous palette
+pub fn changes(palette: &Palette, previous: &Palette) -> Vec<IndexChange> {
+    (0..palette.colors.len().max(previous.colors.len()))
+        .map(|index| {
+            let (old, new) = (previous.color(index), palette.color(index));
+            IndexChange {
+                index,
+                old,
+                new,
+                distance: FloatColor::from_rgb8(old).distance(FloatColor::from_rgb8(new)) * 255.0,
+            }
+        })
+        .filter(|change| change.old != change.new)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(cost: &[Vec<f64>], assigned: &[usize]) -> f64 {
+        assigned.iter().enumerate().map(|(row, &col)| cost[row][col]).sum()
+    }
+
+    #[test]
+    fn known_assignment() {
+        let cost = vec![
+            vec![9.0, 2.0, 7.0, 8.0],
+            vec![6.0, 4.0, 3.0, 7.0],
+            vec![5.0, 8.0, 1.0, 8.0],
+            vec![7.0, 6.0, 9.0, 4.0],
+        ];
+        let assigned = assignment(&cost);
+        assert_eq!(assigned, [1, 0, 2, 3]);
+        assert_eq!(total(&cost, &assigned), 13.0);
+        assert!(assignment(&[]).is_empty());
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        // Every permutation of 6 columns, built by Heap's algorithm
+        fn permutations(k: usize, items: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+            if k == 1 {
+                out.push(items.clone());
+                return;
+            }
+            for i in 0..k {
+                permutations(k - 1, items, out);
+                items.swap(if k.is_multiple_of(2) { i } else { 0 }, k - 1);
+            }
+        }
+        let n = 6;
+        let cost: Vec<Vec<f64>> = (0..n)
+            .map(|row| {
+                (0..n)
+                    .map(|col| ((row * 7 + col * 13 + row * col * 5) % 17) as f64)
+                    .collect()
+            })
+            .collect();
+        let mut all = vec![];
+        permutations(n, &mut (0..n).collect(), &mut all);
+        let best = all
+            .iter()
+            .map(|assigned| total(&cost, assigned))
+            .fold(f64::MAX, f64::min);
+        assert_eq!(total(&cost, &assignment(&cost)), best);
+    }
+
+    #[test]
+    fn keeps_colors_on_their_previous_index() {
+        let previous = Palette::new(vec![[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+        let mut palette = Palette::new(vec![[250, 0, 0], [0, 0, 0], [0, 0, 250], [0, 250, 0]]);
+        palette.locked[0] = true;
+        let result = stabilize(&palette, &previous);
+        assert_eq!(result.colors, [[250, 0, 0], [0, 0, 0], [0, 250, 0], [0, 0, 250]]);
+    }
+}
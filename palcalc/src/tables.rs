@@ -0,0 +1,375 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    colors::{self, FloatColor},
+    config::deserialize_color,
+    palette::Palette,
+};
+
+/// Palette entries the blend tables and the inverse map point to. Fullbright and cycling entries
+/// are left out like in colormaps, unless that leaves none.
+struct Targets {
+    indices: Vec<usize>,
+    colors: Vec<FloatColor>,
+}
+
+impl Targets {
+    fn new(palette: &Palette) -> Targets {
+        let (mut indices, mut colors) = palette.lit_entries();
+        if indices.is_empty() {
+            (indices, colors) = palette.float_colors().into_iter().enumerate().unzip();
+        }
+        Targets { indices, colors }
+    }
+
+    /// Palette index and color of the target nearest to `color`
+    fn nearest(&self, color: FloatColor) -> (u8, FloatColor) {
+        let nearest = colors::nearest(&self.colors, color);
+        (self.indices[nearest] as u8, self.colors[nearest])
+    }
+}
+
+/// 256x256 lookup of palette indices, indexed as `[foreground * 256 + background]`
+pub struct BlendTable {
+    pub data: Vec<u8>,
+    /// Mean distance between the true-color blend and the palette color, in 8-bit RGB units
+    pub mean_error: f64,
+    pub max_error: f64,
+}
+
+impl BlendTable {
+    pub fn build<F>(palette: &Palette, blend: F) -> BlendTable
+    where
+        F: Fn(FloatColor, FloatColor) -> FloatColor + Sync,
+    {
+        let targets = Targets::new(palette);
+        let entries: Vec<FloatColor> = (0..256).map(|i| FloatColor::from_rgb8(palette.color(i))).collect();
+        let (data, errors): (Vec<u8>, Vec<f64>) = (0..256 * 256)
+            .into_par_iter()
+            .map(|i| {
+                let blended = blend(entries[i / 256], entries[i % 256]);
+                let (index, color) = targets.nearest(blended);
+                (index, blended.distance(color) * 255.0)
+            })
+            .unzip();
+
+        // Only pairs of real palette entries count towards the error
+        let used = palette.colors.len();
+        let used_errors: Vec<f64> = (0..used * used).map(|i| errors[i / used * 256 + i % used]).collect();
+        BlendTable {
+            data,
+            mean_error: used_errors.iter().sum::<f64>() / used_errors.len().max(1) as f64,
+            max_error: used_errors.iter().copied().fold(0.0, f64::max),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, &self.data)?;
+        Ok(())
+    }
+
+    /// Renders the table with foreground on the Y axis and background on the X axis
+    pub fn preview(&self, palette: &Palette) -> RgbImage {
+        RgbImage::from_fn(256, 256, |x, y| {
+            Rgb(palette.color(self.data[(y * 256 + x) as usize] as usize))
+        })
+    }
+
+    pub fn write_preview(&self, palette: &Palette, path: &Path) -> Result<()> {
+        self.preview(palette).save(path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranslucencyTarget {
+    /// Foreground opacity in percent
+    pub opacity: f64,
+    pub path: PathBuf,
+    pub preview: Option<PathBuf>,
+}
+
+impl TranslucencyTarget {
+    pub fn build(&self, palette: &Palette) -> BlendTable {
+        let alpha = (self.opacity / 100.0).clamp(0.0, 1.0);
+        BlendTable::build(palette, |fg, bg| bg.mix(fg, alpha))
+    }
+
+    pub fn write(&self, palette: &Palette) -> Result<BlendTable> {
+        let table = self.build(palette);
+        table.write(&self.path)?;
+        if let Some(preview) = &self.preview {
+            table.write_preview(palette, preview)?;
+        }
+        Ok(table)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// Background plus foreground, saturating each channel
+    Additive,
+    /// Background multiplied by foreground per channel
+    Multiply,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlendTarget {
+    pub mode: BlendMode,
+    pub path: PathBuf,
+    pub preview: Option<PathBuf>,
+}
+
+impl BlendTarget {
+    pub fn build(&self, palette: &Palette) -> BlendTable {
+        match self.mode {
+            BlendMode::Additive => BlendTable::build(palette, |fg, bg| FloatColor {
+                r: (bg.r + fg.r).min(1.0),
+                g: (bg.g + fg.g).min(1.0),
+                b: (bg.b + fg.b).min(1.0),
+            }),
+            BlendMode::Multiply => BlendTable::build(palette, |fg, bg| FloatColor {
+                r: bg.r * fg.r,
+                g: bg.g * fg.g,
+                b: bg.b * fg.b,
+            }),
+        }
+    }
+
+    pub fn write(&self, palette: &Palette) -> Result<BlendTable> {
+        let table = self.build(palette);
+        table.write(&self.path)?;
+        if let Some(preview) = &self.preview {
+            table.write_preview(palette, preview)?;
+        }
+        Ok(table)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShadeCurve {
+    /// Even steps towards the fog color
+    #[default]
+    Linear,
+    /// Brightness falls off as (1 - level)^gamma
+    Gamma,
+    /// Linear steps computed at 6-bit VGA precision like Build's palookup tables
+    Build,
+}
+
+fn default_tint() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+fn default_gamma() -> f64 {
+    2.2
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColormapTarget {
+    pub name: Option<String>,
+    pub path: PathBuf,
+    pub preview: Option<PathBuf>,
+    /// Light color multiplied with every palette entry
+    #[serde(default = "default_tint", deserialize_with = "deserialize_color")]
+    pub tint: [u8; 3],
+    /// Color the shades fade towards
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub fog: [u8; 3],
+    #[serde(default)]
+    pub curve: ShadeCurve,
+    #[serde(default = "default_gamma")]
+    pub gamma: f64,
+    /// Number of light levels, defaults to the global `shades`
+    pub shades: Option<u32>,
+}
+
+/// Light levels from brightest to darkest, `levels * 256` palette indices
+pub struct Colormap {
+    pub levels: u32,
+    pub data: Vec<u8>,
+}
+
+impl ColormapTarget {
+    pub fn new(path: PathBuf) -> ColormapTarget {
+        ColormapTarget {
+            name: None,
+            preview: Some(path.with_extension("png")),
+            path,
+            tint: default_tint(),
+            fog: [0, 0, 0],
+            curve: ShadeCurve::default(),
+            gamma: default_gamma(),
+            shades: None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+        }
+    }
+
+    fn shade(&self, color: FloatColor, fog: FloatColor, level: u32, levels: u32) -> FloatColor {
+        let t = level as f64 / levels as f64;
+        match self.curve {
+            ShadeCurve::Linear => color.mix(fog, t),
+            ShadeCurve::Gamma => color.mix(fog, 1.0 - (1.0 - t).powf(self.gamma)),
+            ShadeCurve::Build => {
+                let (n, j) = (levels as i32, level as i32);
+                let channel = |c: f64, f: f64| {
+                    let c6 = (c * 255.0).round() as i32 >> 2;
+                    let f6 = (f * 255.0).round() as i32 >> 2;
+                    let v = (c6 * (n - j) + f6 * j + n / 2) / n;
+                    ((v << 2) | (v >> 4)) as f64 / 255.0
+                };
+                FloatColor {
+                    r: channel(color.r, fog.r),
+                    g: channel(color.g, fog.g),
+                    b: channel(color.b, fog.b),
+                }
+            }
+        }
+    }
+
+    pub fn build(&self, palette: &Palette, default_shades: u32) -> Colormap {
+        let levels = self.shades.unwrap_or(default_shades).max(1);
+        // Shaded colors never land on fullbright or cycling entries, and those entries keep their index at
+        // every level so that they stay bright and keep animating
+        let (lit_indices, lit_colors) = palette.lit_entries();
+        let tint = FloatColor::from_rgb8(self.tint);
+        let fog = FloatColor::from_rgb8(self.fog);
+        let data = (0..levels as usize * 256)
+            .into_par_iter()
+            .map(|i| {
+                if palette.is_fullbright(i % 256) || palette.is_cycling(i % 256) || lit_colors.is_empty() {
+                    return (i % 256) as u8;
+                }
+                let base = FloatColor::from_rgb8(palette.color(i % 256));
+                let lit = FloatColor {
+                    r: base.r * tint.r,
+                    g: base.g * tint.g,
+                    b: base.b * tint.b,
+                };
+                lit_indices[colors::nearest(&lit_colors, self.shade(lit, fog, (i / 256) as u32, levels))] as u8
+            })
+            .collect();
+        Colormap { levels, data }
+    }
+
+    pub fn write(&self, palette: &Palette, default_shades: u32) -> Result<Colormap> {
+        let colormap = self.build(palette, default_shades);
+        fs::write(&self.path, &colormap.data)?;
+        if let Some(preview) = &self.preview {
+            colormap.write_preview(palette, preview)?;
+        }
+        Ok(colormap)
+    }
+}
+
+impl Colormap {
+    /// Renders the colormap with one 4 pixel high row per light level
+    pub fn preview(&self, palette: &Palette) -> RgbImage {
+        RgbImage::from_fn(256, self.levels * 4, |x, y| {
+            Rgb(palette.color(self.data[(y / 4 * 256 + x) as usize] as usize))
+        })
+    }
+
+    pub fn write_preview(&self, palette: &Palette, path: &Path) -> Result<()> {
+        self.preview(palette).save(path)?;
+        Ok(())
+    }
+}
+
+fn default_inverse_bits() -> u32 {
+    6
+}
+
+/// 3D grid mapping true-color values to palette indices.
+/// Cells are stored red-major: `(r >> shift) << 2 * bits | (g >> shift) << bits | (b >> shift)`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InverseMapTarget {
+    pub path: PathBuf,
+    /// Bits per channel, from 5 to 7
+    #[serde(default = "default_inverse_bits")]
+    pub bits: u32,
+}
+
+impl InverseMapTarget {
+    pub fn check(&self) -> Result<()> {
+        if !(5..=7).contains(&self.bits) {
+            return Err(anyhow!("inverse map needs 5 to 7 bits per channel, got {}", self.bits));
+        }
+        Ok(())
+    }
+
+    pub fn build(&self, palette: &Palette) -> Result<Vec<u8>> {
+        self.check()?;
+        let targets = Targets::new(palette);
+        let bits = self.bits;
+        let size = 1usize << bits;
+        let step = 1usize << (8 - bits);
+        // Center of the 8-bit value range covered by a cell
+        let center = |v: usize| ((v * step) as f64 + (step - 1) as f64 / 2.0) / 255.0;
+        Ok((0..size * size * size)
+            .into_par_iter()
+            .map(|i| {
+                let color = FloatColor {
+                    r: center(i >> (2 * bits)),
+                    g: center((i >> bits) & (size - 1)),
+                    b: center(i & (size - 1)),
+                };
+                targets.nearest(color).0
+            })
+            .collect())
+    }
+
+    /// Writes the map and returns its size in bytes
+    pub fn write(&self, palette: &Palette) -> Result<usize> {
+        let data = self.build(palette)?;
+        fs::write(&self.path, &data)?;
+        Ok(data.len())
+    }
+}
+
+/// Parses OPACITY=PATH, the preview is written next to PATH as a PNG
+pub fn parse_translucency(s: &str) -> Result<TranslucencyTarget> {
+    let (opacity, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected OPACITY=PATH, got \"{}\"", s))?;
+    let path = PathBuf::from(path);
+    Ok(TranslucencyTarget {
+        opacity: opacity.trim_end_matches('%').parse()?,
+        preview: Some(path.with_extension("png")),
+        path,
+    })
+}
+
+/// Parses MODE=PATH, the preview is written next to PATH as a PNG
+pub fn parse_blend(s: &str) -> Result<BlendTarget> {
+    let (mode, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected MODE=PATH, got \"{}\"", s))?;
+    let path = PathBuf::from(path);
+    Ok(BlendTarget {
+        mode: BlendMode::from_str(mode, true).map_err(|e| anyhow!(e))?,
+        preview: Some(path.with_extension("png")),
+        path,
+    })
+}